@@ -0,0 +1,4 @@
+fn main() {
+    // マイグレーションを追加した際に埋め込み直す
+    println!("cargo:rerun-if-changed=migrations");
+}
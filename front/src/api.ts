@@ -4,7 +4,8 @@ const root = '';
 
 const mediaIdsSchema = z.object({
   ids: z.array(z.string()),
-  last: z.number(),
+  cursor: z.string().nullable(),
+  has_more: z.boolean(),
 });
 
 const buildUrl = (path: string) => `${root}/${path}`;
@@ -17,12 +18,13 @@ export const paths = {
   },
 };
 
-export const getMediaIds = async (begin: number | null, count = 100) => {
-  // クエリストリング作るところなんとかせえ
-  const path = buildUrl(paths.media.ids) + '?count=' + count;
+export const getMediaIds = async (cursor: string | null, count = 100) => {
+  const params = new URLSearchParams({ count: `${count}` });
+  if (cursor != null) {
+    params.set('cursor', cursor);
+  }
   try {
-    const path_ = begin != null ? `${path}&begin=${begin}` : path;
-    const r = await fetch(path_);
+    const r = await fetch(`${buildUrl(paths.media.ids)}?${params}`);
     const data = await r.json();
     const parsed = mediaIdsSchema.safeParse(data);
     if (parsed.success) {
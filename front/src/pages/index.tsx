@@ -29,14 +29,16 @@ const Images = ({ ids }: { ids: ReadonlyArray<string> }): JSX.Element => {
 
 const Home = (): JSX.Element => {
   const [ids, setIds] = useState<ReadonlyArray<string>>([]);
-  const next = useRef<number | null>(null);
+  const next = useRef<string | null>(null);
+  const [hasMore, setHasMore] = useState(true);
 
   const loadNext = useCallback(() => {
     getMediaIds(next.current).then((mediaIds) => {
       if (mediaIds == null) {
         return;
       }
-      next.current = mediaIds.last;
+      next.current = mediaIds.cursor ?? next.current;
+      setHasMore(mediaIds.has_more);
       const prev = ids ?? [];
       const ids_ = (mediaIds.ids ?? []).filter((id) => !prev.includes(id));
       setIds([...prev, ...ids_]);
@@ -50,7 +52,7 @@ const Home = (): JSX.Element => {
   return (
     <div>
       <Images ids={ids} />
-      <button onClick={loadNext}>
+      <button onClick={loadNext} disabled={!hasMore}>
         次を読み込む
       </button>
     </div>
@@ -0,0 +1,13 @@
+-- Add up migration script here
+CREATE TABLE tags (
+    tag_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE media_tags (
+    media_id TEXT NOT NULL REFERENCES metas (media_id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tags (tag_id) ON DELETE CASCADE,
+    PRIMARY KEY (media_id, tag_id)
+);
+
+CREATE INDEX media_tags_tag_id ON media_tags (tag_id);
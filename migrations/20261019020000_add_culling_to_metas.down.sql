@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE metas DROP COLUMN label;
+ALTER TABLE metas DROP COLUMN favorite;
+ALTER TABLE metas DROP COLUMN rating;
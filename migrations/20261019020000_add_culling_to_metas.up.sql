@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN rating INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE metas ADD COLUMN favorite BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE metas ADD COLUMN label INTEGER;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE metas_fts;
+ALTER TABLE metas DROP COLUMN description;
+ALTER TABLE metas DROP COLUMN title;
@@ -0,0 +1,28 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN title TEXT;
+ALTER TABLE metas ADD COLUMN description TEXT;
+
+-- 日本語でも部分一致で検索できるように trigram を使う
+CREATE VIRTUAL TABLE metas_fts USING fts5 (
+    media_id UNINDEXED,
+    origin,
+    title,
+    description,
+    tags,
+    attributes,
+    tokenize = 'trigram'
+);
+
+INSERT INTO metas_fts (media_id, origin, title, description, tags, attributes)
+SELECT
+    media_id,
+    origin,
+    '',
+    '',
+    coalesce((
+        SELECT group_concat(tags.name, ' ') FROM media_tags
+        JOIN tags USING (tag_id)
+        WHERE media_tags.media_id = metas.media_id
+    ), ''),
+    coalesce((SELECT group_concat(value, ' ') FROM json_each(metas.attributes)), '')
+FROM metas;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE metas DROP COLUMN height;
+ALTER TABLE metas DROP COLUMN width;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN width INTEGER;
+ALTER TABLE metas ADD COLUMN height INTEGER;
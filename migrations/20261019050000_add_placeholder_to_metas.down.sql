@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE metas DROP COLUMN aspect_ratio;
+ALTER TABLE metas DROP COLUMN blurhash;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN blurhash TEXT;
+ALTER TABLE metas ADD COLUMN aspect_ratio REAL;
+
+UPDATE metas SET aspect_ratio = CAST(width AS REAL) / height
+WHERE width IS NOT NULL AND height IS NOT NULL AND height > 0;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE metas DROP COLUMN palette;
+ALTER TABLE metas DROP COLUMN dominant_color;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN dominant_color INTEGER;
+ALTER TABLE metas ADD COLUMN palette JSON;
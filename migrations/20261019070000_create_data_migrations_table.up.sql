@@ -0,0 +1,6 @@
+-- Add up migration script here
+CREATE TABLE data_migrations (
+    version INTEGER PRIMARY KEY,
+    description TEXT NOT NULL,
+    applied_at DATETIME NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE metas DROP COLUMN longitude;
+ALTER TABLE metas DROP COLUMN latitude;
+ALTER TABLE metas DROP COLUMN device;
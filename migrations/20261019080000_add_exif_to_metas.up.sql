@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN device TEXT;
+ALTER TABLE metas ADD COLUMN latitude REAL;
+ALTER TABLE metas ADD COLUMN longitude REAL;
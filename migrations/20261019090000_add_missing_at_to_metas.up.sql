@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN missing_at DATETIME;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE quarantine (
+    origin TEXT PRIMARY KEY NOT NULL,
+    size INTEGER NOT NULL,
+    modified DATETIME NOT NULL,
+    error TEXT NOT NULL,
+    quarantined_at DATETIME NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX jobs_status_next_run_at;
+DROP TABLE jobs;
@@ -0,0 +1,14 @@
+-- Add up migration script here
+CREATE TABLE jobs (
+    job_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    origin TEXT NOT NULL UNIQUE,
+    status INTEGER NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    media_id TEXT,
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    next_run_at DATETIME NOT NULL
+);
+
+CREATE INDEX jobs_status_next_run_at ON jobs (status, next_run_at);
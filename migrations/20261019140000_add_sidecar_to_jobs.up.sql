@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE jobs ADD COLUMN sidecar BOOLEAN NOT NULL DEFAULT 0
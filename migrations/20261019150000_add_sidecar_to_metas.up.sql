@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE metas ADD COLUMN sidecar BOOLEAN NOT NULL DEFAULT 0;
+
+-- JSON を読んで取り込んだジョブのものは、JSON から取り込んだとみなす
+UPDATE metas SET sidecar = 1 WHERE origin IN (SELECT origin FROM jobs WHERE sidecar = 1)
@@ -0,0 +1,466 @@
+use crate::{
+    db::{self, DatabaseOption},
+    media::{MediaGenerateOption, ScanOption, ThumbOption, DEFAULT_EXCLUDE, DEFAULT_MAX_DEPTH},
+};
+use anyhow::Result;
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// 指定しない場合に読み込む設定ファイル、なければデフォルトのまま
+pub const DEFAULT_CONFIG_FILE: &str = "miruku.toml";
+
+/// ゴミ箱に残す最大の日数、これより長いと日時の計算が溢れる場合がある
+pub const MAX_RETENTION_DAYS: i64 = 100 * 365;
+
+/// `miruku.toml` の設定
+/// デフォルト < 設定ファイル < 環境変数 (`MIRUKU_*`) < コマンドライン引数 の順に上書きする
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub ingest: IngestConfig,
+    pub scan: ScanConfig,
+    pub thumbnail: ThumbnailConfig,
+    pub ftp: FtpConfig,
+    pub trash: TrashConfig,
+    /// 読み込んだ設定ファイル
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// フロントエンドのビルド結果
+    pub front_dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// コネクションプールの最大接続数
+    pub pool_size: u32,
+    /// ロックされている場合に待つミリ秒
+    pub busy_timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestConfig {
+    /// 取り込むワーカーの数
+    pub workers: usize,
+    /// 以前の `scan.max_depth`、読み込んだら `scan.max_depth` に移す
+    #[serde(skip_serializing)]
+    pub recursive_depth: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    /// ディレクトリを探す最大の深さ、`0` の場合は無制限
+    pub max_depth: u32,
+    /// 指定した場合は、どれかに一致するファイルだけを取り込む
+    pub include: Vec<String>,
+    /// 一致するファイルとディレクトリを除く
+    pub exclude: Vec<String>,
+    /// `.` で始まるファイルとディレクトリを除く
+    pub skip_hidden: bool,
+    /// シンボリックリンクを辿る、ループしている場合は辿らない
+    pub follow_symlinks: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThumbnailConfig {
+    /// リサイズ後の長辺
+    pub size: u32,
+    /// nearest, triangle, catmullrom, gaussian, lanczos3
+    pub filter: String,
+    pub blurhash_size: u32,
+    pub blurhash_components_x: u32,
+    pub blurhash_components_y: u32,
+    pub palette_size: usize,
+    pub palette_quantize_bits: u32,
+    pub palette_min_ratio: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FtpConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// PASV で返すアドレス、NAT の内側で動かす場合に指定する
+    pub pasv_address: Option<IpAddr>,
+    pub pasv_min_port: u16,
+    pub pasv_max_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    /// ゴミ箱に入れてからこの日数が経過したものを `purge` で削除する
+    pub retention_days: i64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from("./data"),
+            server: Default::default(),
+            database: Default::default(),
+            ingest: Default::default(),
+            scan: Default::default(),
+            thumbnail: Default::default(),
+            ftp: Default::default(),
+            trash: Default::default(),
+            source: None,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: Ipv4Addr::UNSPECIFIED.into(),
+            port: 9999,
+            front_dir: PathBuf::from("./front/out"),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        let option = DatabaseOption::default();
+        DatabaseConfig {
+            pool_size: option.pool_size,
+            busy_timeout: option.busy_timeout.as_millis() as u64,
+        }
+    }
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            workers: 2,
+            recursive_depth: None,
+        }
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            max_depth: DEFAULT_MAX_DEPTH,
+            include: vec![],
+            exclude: DEFAULT_EXCLUDE.iter().map(|pattern| pattern.to_string()).collect(),
+            skip_hidden: true,
+            follow_symlinks: true,
+        }
+    }
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        let option = ThumbOption::default();
+        let (blurhash_components_x, blurhash_components_y) = option.blurhash_components;
+        ThumbnailConfig {
+            size: option.size,
+            filter: "nearest".to_string(),
+            blurhash_size: option.blurhash_size,
+            blurhash_components_x,
+            blurhash_components_y,
+            palette_size: option.palette_size,
+            palette_quantize_bits: option.palette_quantize_bits,
+            palette_min_ratio: option.palette_min_ratio,
+        }
+    }
+}
+
+impl Default for FtpConfig {
+    fn default() -> Self {
+        FtpConfig {
+            address: Ipv4Addr::UNSPECIFIED.into(),
+            port: 21,
+            pasv_address: None,
+            pasv_min_port: 21100,
+            pasv_max_port: 21110,
+        }
+    }
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig { retention_days: 30 }
+    }
+}
+
+impl Config {
+    /// 設定ファイルを読み込んで、環境変数で上書きする
+    /// `path` を指定しない場合は `./miruku.toml` があれば読み込む
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => Some(path.to_owned()),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.is_file()),
+        };
+
+        let mut config = match &path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+                let mut config = toml::from_str::<Config>(&content)
+                    .map_err(|e| anyhow!("invalid config {}: {}", path.display(), e))?;
+                // 以前の `ingest.recursive_depth` は `scan.max_depth` がない場合に使う
+                if let Some(depth) = config.ingest.recursive_depth.take() {
+                    log::warn!("ingest.recursive_depth is deprecated, use scan.max_depth");
+                    let value = toml::from_str::<toml::Value>(&content)?;
+                    let scan = value.get("scan");
+                    if scan.and_then(|scan| scan.get("max_depth")).is_none() {
+                        config.scan.max_depth = depth;
+                    }
+                }
+                config
+            }
+            None => Config::default(),
+        };
+        config.source = path;
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// `MIRUKU_{セクション}_{キー}` の環境変数で上書きする
+    fn apply_env(&mut self) -> Result<()> {
+        env("MIRUKU_DATA_DIR", &mut self.data_dir)?;
+
+        env("MIRUKU_SERVER_ADDRESS", &mut self.server.address)?;
+        env("MIRUKU_SERVER_PORT", &mut self.server.port)?;
+        env("MIRUKU_SERVER_FRONT_DIR", &mut self.server.front_dir)?;
+
+        env("MIRUKU_DATABASE_POOL_SIZE", &mut self.database.pool_size)?;
+        env("MIRUKU_DATABASE_BUSY_TIMEOUT", &mut self.database.busy_timeout)?;
+
+        env("MIRUKU_INGEST_WORKERS", &mut self.ingest.workers)?;
+
+        // 以前の名前も読む、両方あれば `MIRUKU_SCAN_MAX_DEPTH` を優先する
+        env("MIRUKU_INGEST_RECURSIVE_DEPTH", &mut self.scan.max_depth)?;
+        env("MIRUKU_SCAN_MAX_DEPTH", &mut self.scan.max_depth)?;
+        // パターンはカンマで区切る
+        if let Some(include) = env_value::<String>("MIRUKU_SCAN_INCLUDE")? {
+            self.scan.include = split_patterns(&include);
+        }
+        if let Some(exclude) = env_value::<String>("MIRUKU_SCAN_EXCLUDE")? {
+            self.scan.exclude = split_patterns(&exclude);
+        }
+        env("MIRUKU_SCAN_SKIP_HIDDEN", &mut self.scan.skip_hidden)?;
+        env("MIRUKU_SCAN_FOLLOW_SYMLINKS", &mut self.scan.follow_symlinks)?;
+
+        let thumbnail = &mut self.thumbnail;
+        env("MIRUKU_THUMBNAIL_SIZE", &mut thumbnail.size)?;
+        env("MIRUKU_THUMBNAIL_FILTER", &mut thumbnail.filter)?;
+        env("MIRUKU_THUMBNAIL_BLURHASH_SIZE", &mut thumbnail.blurhash_size)?;
+        env(
+            "MIRUKU_THUMBNAIL_BLURHASH_COMPONENTS_X",
+            &mut thumbnail.blurhash_components_x,
+        )?;
+        env(
+            "MIRUKU_THUMBNAIL_BLURHASH_COMPONENTS_Y",
+            &mut thumbnail.blurhash_components_y,
+        )?;
+        env("MIRUKU_THUMBNAIL_PALETTE_SIZE", &mut thumbnail.palette_size)?;
+        env(
+            "MIRUKU_THUMBNAIL_PALETTE_QUANTIZE_BITS",
+            &mut thumbnail.palette_quantize_bits,
+        )?;
+        env(
+            "MIRUKU_THUMBNAIL_PALETTE_MIN_RATIO",
+            &mut thumbnail.palette_min_ratio,
+        )?;
+
+        env("MIRUKU_FTP_ADDRESS", &mut self.ftp.address)?;
+        env("MIRUKU_FTP_PORT", &mut self.ftp.port)?;
+        // `.env.example` の vsftpd の時の名前も読む、両方あれば `MIRUKU_` を優先する
+        for name in ["PASV_ADDRESS", "MIRUKU_FTP_PASV_ADDRESS"] {
+            if let Some(pasv_address) = env_value(name)? {
+                self.ftp.pasv_address = Some(pasv_address);
+            }
+        }
+        env("PASV_MIN_PORT", &mut self.ftp.pasv_min_port)?;
+        env("MIRUKU_FTP_PASV_MIN_PORT", &mut self.ftp.pasv_min_port)?;
+        env("PASV_MAX_PORT", &mut self.ftp.pasv_max_port)?;
+        env("MIRUKU_FTP_PASV_MAX_PORT", &mut self.ftp.pasv_max_port)?;
+
+        env("MIRUKU_TRASH_RETENTION_DAYS", &mut self.trash.retention_days)?;
+
+        Ok(())
+    }
+
+    /// コマンドライン引数で上書きした後に、値が正しいか確かめる
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.data_dir.as_os_str().is_empty(),
+            "data_dir must not be empty"
+        );
+        ensure!(
+            self.database.pool_size > 0,
+            "database.pool_size must be greater than 0"
+        );
+        ensure!(
+            self.ingest.workers > 0,
+            "ingest.workers must be greater than 0"
+        );
+        let _ = self.scan.scan_option()?;
+
+        let thumbnail = &self.thumbnail;
+        ensure!(thumbnail.size > 0, "thumbnail.size must be greater than 0");
+        let _ = thumbnail.filter_type()?;
+        ensure!(
+            thumbnail.blurhash_size > 0,
+            "thumbnail.blurhash_size must be greater than 0"
+        );
+        // BlurHash の成分数は 1 から 9 まで
+        ensure!(
+            (1..=9).contains(&thumbnail.blurhash_components_x)
+                && (1..=9).contains(&thumbnail.blurhash_components_y),
+            "thumbnail.blurhash_components_x and _y must be between 1 and 9"
+        );
+        ensure!(
+            thumbnail.palette_size > 0,
+            "thumbnail.palette_size must be greater than 0"
+        );
+        ensure!(
+            (1..=8).contains(&thumbnail.palette_quantize_bits),
+            "thumbnail.palette_quantize_bits must be between 1 and 8"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&thumbnail.palette_min_ratio),
+            "thumbnail.palette_min_ratio must be between 0 and 1"
+        );
+
+        ensure!(
+            self.ftp.pasv_min_port <= self.ftp.pasv_max_port,
+            "ftp.pasv_min_port must not be greater than ftp.pasv_max_port"
+        );
+        ensure!(
+            (0..=MAX_RETENTION_DAYS).contains(&self.trash.retention_days),
+            "trash.retention_days must be between 0 and {}",
+            MAX_RETENTION_DAYS
+        );
+
+        Ok(())
+    }
+
+    pub fn database_option(&self) -> DatabaseOption {
+        DatabaseOption {
+            pool_size: self.database.pool_size,
+            busy_timeout: Duration::from_millis(self.database.busy_timeout),
+        }
+    }
+
+    pub fn generate_option(&self) -> Result<MediaGenerateOption> {
+        let thumbnail = &self.thumbnail;
+        Ok(MediaGenerateOption {
+            thumb: ThumbOption {
+                size: thumbnail.size,
+                filter: thumbnail.filter_type()?,
+                blurhash_size: thumbnail.blurhash_size,
+                blurhash_components: (
+                    thumbnail.blurhash_components_x,
+                    thumbnail.blurhash_components_y,
+                ),
+                palette_size: thumbnail.palette_size,
+                palette_quantize_bits: thumbnail.palette_quantize_bits,
+                palette_min_ratio: thumbnail.palette_min_ratio,
+            },
+            scan: self.scan.scan_option()?,
+            sidecar: false,
+        })
+    }
+
+    /// `data_dir` のデータベースのコネクションプールを作る
+    pub async fn create_pool(&self) -> Result<SqlitePool> {
+        db::create_pool(&self.data_dir, &self.database_option()).await
+    }
+
+    /// `data_dir` のデータベースに接続する、適用していないマイグレーションがあれば適用する
+    pub async fn connect(&self) -> Result<SqliteConnection> {
+        db::create_connection(&self.data_dir, &self.database_option()).await
+    }
+
+    /// `data_dir` のデータベースにマイグレーションを適用せずに接続する
+    pub async fn connect_without_migrations(&self) -> Result<SqliteConnection> {
+        db::open_connection(&self.data_dir, &self.database_option()).await
+    }
+}
+
+impl ScanConfig {
+    pub fn scan_option(&self) -> Result<ScanOption> {
+        let max_depth = Some(self.max_depth).filter(|max_depth| *max_depth > 0);
+        ScanOption::new(
+            max_depth,
+            &self.include,
+            &self.exclude,
+            self.skip_hidden,
+            self.follow_symlinks,
+        )
+    }
+}
+
+impl ThumbnailConfig {
+    pub fn filter_type(&self) -> Result<FilterType> {
+        let filter = match self.filter.to_lowercase().as_str() {
+            "nearest" => FilterType::Nearest,
+            "triangle" => FilterType::Triangle,
+            "catmullrom" => FilterType::CatmullRom,
+            "gaussian" => FilterType::Gaussian,
+            "lanczos3" => FilterType::Lanczos3,
+            _ => bail!("invalid thumbnail.filter: {}", self.filter),
+        };
+        Ok(filter)
+    }
+}
+
+fn split_patterns(patterns: &str) -> Vec<String> {
+    patterns
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// 環境変数が設定されていれば `value` を上書きする
+fn env<T>(name: &str, value: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Some(parsed) = env_value(name)? {
+        *value = parsed;
+    }
+    Ok(())
+}
+
+fn env_value<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = match std::env::var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    match value.parse() {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(e) => bail!("invalid {}: {}: {}", name, value, e),
+    }
+}
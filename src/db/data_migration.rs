@@ -0,0 +1,195 @@
+use crate::media::get_exif_local_date;
+use anyhow::Result;
+use chrono::{prelude::*, LocalResult};
+use sqlx::{prelude::*, query_as, SqliteConnection};
+use std::{collections::HashMap, path::Path};
+
+/// 既存のデータを直すためのマイグレーション
+/// スキーマのマイグレーションと同じように、適用したものは `data_migrations` に記録する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataMigration {
+    /// Local のまま UTC として保存していた日時を UTC に直す
+    /// 取り込んだ時期によって既に UTC のものがあるので、`--include` で指定した場合だけ適用する
+    LocalDateToUtc,
+}
+
+impl DataMigration {
+    /// 適用する順に並べたもの
+    pub const ALL: &'static [DataMigration] = &[DataMigration::LocalDateToUtc];
+
+    pub fn version(&self) -> i64 {
+        match self {
+            DataMigration::LocalDateToUtc => 1,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            DataMigration::LocalDateToUtc => "local date to utc",
+        }
+    }
+
+    /// 明示的に指定した場合だけ適用するもの
+    pub fn opt_in(&self) -> bool {
+        match self {
+            DataMigration::LocalDateToUtc => true,
+        }
+    }
+
+    /// データを変更して、変更内容を返す
+    async fn apply(&self, conn: &mut SqliteConnection) -> Result<Vec<String>> {
+        match self {
+            DataMigration::LocalDateToUtc => local_date_to_utc(conn).await,
+        }
+    }
+}
+
+/// データマイグレーションの適用状況
+#[derive(Debug, Clone)]
+pub struct DataMigrationStatus {
+    pub migration: DataMigration,
+    pub applied_at: Option<NaiveDateTime>,
+}
+
+/// データマイグレーションごとの適用状況を返す
+pub async fn data_migration_status(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<DataMigrationStatus>> {
+    let applied: Vec<(i64, NaiveDateTime)> =
+        query_as("select version, applied_at from data_migrations")
+            .fetch_all(conn)
+            .await?;
+    let applied = applied.into_iter().collect::<HashMap<_, _>>();
+
+    let status = DataMigration::ALL
+        .iter()
+        .map(|migration| DataMigrationStatus {
+            migration: *migration,
+            applied_at: applied.get(&migration.version()).copied(),
+        })
+        .collect();
+    Ok(status)
+}
+
+/// 適用していないデータマイグレーションを一つずつトランザクションの中で適用する
+/// `opt_in` のものは `include` にバージョンがある場合だけ適用する
+/// `dry_run` の場合は変更内容を返すだけでロールバックする
+pub async fn run_data_migrations(
+    conn: &mut SqliteConnection,
+    include: &[i64],
+    dry_run: bool,
+) -> Result<Vec<(DataMigration, Vec<String>)>> {
+    let mut results = vec![];
+    for status in data_migration_status(conn).await? {
+        let migration = status.migration;
+        if status.applied_at.is_some()
+            || (migration.opt_in() && !include.contains(&migration.version()))
+        {
+            continue;
+        }
+
+        let mut tx = Connection::begin(&mut *conn).await?;
+        let changes = migration.apply(&mut tx).await?;
+        if dry_run {
+            tx.rollback().await?;
+        } else {
+            record(&mut tx, migration).await?;
+            tx.commit().await?;
+        }
+        results.push((migration, changes));
+    }
+    Ok(results)
+}
+
+/// 適用していないデータマイグレーションを、データを変更せずに適用済みとして記録する
+pub async fn mark_data_migrations_applied(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<DataMigration>> {
+    let mut marked = vec![];
+    for status in data_migration_status(conn).await? {
+        if status.applied_at.is_none() {
+            record(conn, status.migration).await?;
+            marked.push(status.migration);
+        }
+    }
+    Ok(marked)
+}
+
+/// メディアが一件もなければ直すデータもないので、全て適用済みにする
+/// 新しく作ったデータベースで古いデータ向けのマイグレーションが走らないようにする
+pub async fn mark_data_migrations_applied_if_empty(conn: &mut SqliteConnection) -> Result<()> {
+    let (count,): (i64,) = query_as("select count(*) from metas")
+        .fetch_one(&mut *conn)
+        .await?;
+    if count == 0 {
+        let _ = mark_data_migrations_applied(conn).await?;
+    }
+    Ok(())
+}
+
+async fn record(conn: &mut SqliteConnection, migration: DataMigration) -> Result<()> {
+    let _ = sqlx::query(
+        "insert into data_migrations (version, description, applied_at) values (?, ?, ?)",
+    )
+    .bind(migration.version())
+    .bind(migration.description())
+    .bind(Utc::now().naive_utc())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// date が Local のまま Utc のものとして放り込まれているので、Local -> Utc に変換してアップデートする
+/// EXIF の日付と一致するもの (UTC にする前に取り込んだもの) だけを直し、既に UTC のものは二重にずらさない
+async fn local_date_to_utc(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+    #[derive(FromRow, Debug, Clone)]
+    struct MediaIdWithDateRow {
+        pub media_id: String,
+        pub origin: String,
+        pub date: NaiveDateTime,
+    }
+
+    let medias: Vec<MediaIdWithDateRow> = query_as(
+        r#"
+        select media_id, origin, date from metas
+        order by date desc
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut changes = Vec::with_capacity(medias.len());
+    let mut skipped = 0;
+    for MediaIdWithDateRow {
+        media_id,
+        origin,
+        date,
+    } in medias
+    {
+        // EXIF がなければ、いつ取り込んだものか分からないので触らない
+        match get_exif_local_date(Path::new(&origin)).await {
+            Ok(local) if local == date => {}
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        }
+        let utc = match Local.from_local_datetime(&date) {
+            LocalResult::Single(utc) => utc.naive_utc(),
+            _ => bail!("ambiguous local date: {} {}", media_id, date),
+        };
+        if utc == date {
+            continue;
+        }
+        let _ = sqlx::query("update metas set date = ? where media_id = ?")
+            .bind(utc)
+            .bind(&media_id)
+            .execute(&mut *conn)
+            .await?;
+        changes.push(format!("{}: {} -> {}", media_id, date, utc));
+    }
+    if skipped > 0 {
+        changes.push(format!("skipped {} media not dated by local EXIF time", skipped));
+    }
+    Ok(changes)
+}
@@ -0,0 +1,156 @@
+mod data_migration;
+
+pub use data_migration::*;
+
+use anyhow::Result;
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    ConnectOptions, SqliteConnection, SqlitePool,
+};
+use std::{collections::HashMap, path::Path, time::Duration};
+
+// データベースのファイル名
+const DATABASE_FILE_NAME: &str = "db.sqlite3";
+
+/// `migrations/` をバイナリに埋め込む
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// データベースに接続する際の設定
+#[derive(Debug, Clone)]
+pub struct DatabaseOption {
+    /// コネクションプールの最大接続数
+    pub pool_size: u32,
+    /// ロックされている場合に待つ時間
+    pub busy_timeout: Duration,
+}
+
+impl Default for DatabaseOption {
+    fn default() -> Self {
+        DatabaseOption {
+            pool_size: 4,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl DatabaseOption {
+    /// WAL にして、読み込みと書き込みが同時にできるようにする
+    fn connect_options(&self, data_directory: &Path) -> SqliteConnectOptions {
+        SqliteConnectOptions::new()
+            .filename(data_directory.join(DATABASE_FILE_NAME))
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(self.busy_timeout)
+    }
+}
+
+// SQLite3データベースのコネクションプールを返す
+// データベースがなければ作成して、マイグレーションを適用する
+// データマイグレーションは既存のデータを書き換えるので、明示的に `data-migrate` で適用する
+pub async fn create_pool(data_directory: &Path, option: &DatabaseOption) -> Result<SqlitePool> {
+    tokio::fs::create_dir_all(data_directory).await?;
+    let pool = SqlitePoolOptions::new()
+        .max_connections(option.pool_size)
+        .connect_with(
+            option
+                .connect_options(data_directory)
+                .create_if_missing(true),
+        )
+        .await?;
+    MIGRATOR.run(&pool).await?;
+    mark_data_migrations_applied_if_empty(&mut *pool.acquire().await?).await?;
+    Ok(pool)
+}
+
+// SQLite3データベースを返す
+// コネクションプールと同じように、データベースがなければ作成して、マイグレーションを適用する
+pub async fn create_connection(
+    data_directory: &Path,
+    option: &DatabaseOption,
+) -> Result<SqliteConnection> {
+    tokio::fs::create_dir_all(data_directory).await?;
+    let mut conn = option
+        .connect_options(data_directory)
+        .create_if_missing(true)
+        .connect()
+        .await?;
+    MIGRATOR.run(&mut conn).await?;
+    mark_data_migrations_applied_if_empty(&mut conn).await?;
+    Ok(conn)
+}
+
+// マイグレーションを適用せずに、既存のSQLite3データベースを返す
+// マイグレーションの状況を確かめたり、戻したりする場合に使う
+pub async fn open_connection(
+    data_directory: &Path,
+    option: &DatabaseOption,
+) -> Result<SqliteConnection> {
+    let conn = option
+        .connect_options(data_directory)
+        .connect()
+        .await?;
+    Ok(conn)
+}
+
+/// マイグレーションの適用状況
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+    /// 適用した時とファイルの内容が異なる
+    pub mismatched: bool,
+}
+
+/// 埋め込んだマイグレーションごとの適用状況を返す
+pub async fn migration_status(conn: &mut SqliteConnection) -> Result<Vec<MigrationStatus>> {
+    conn.ensure_migrations_table().await?;
+    let applied = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum))
+        .collect::<HashMap<_, _>>();
+
+    let status = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| {
+            let checksum = applied.get(&migration.version);
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied: checksum.is_some(),
+                mismatched: checksum
+                    .map(|checksum| *checksum != migration.checksum)
+                    .unwrap_or(false),
+            }
+        })
+        .collect();
+    Ok(status)
+}
+
+/// 最後に適用したマイグレーションを一つ戻す
+/// 戻したマイグレーションのバージョンを返す、適用済みのものがなければ `None`
+pub async fn revert_last_migration(conn: &mut SqliteConnection) -> Result<Option<i64>> {
+    conn.ensure_migrations_table().await?;
+    let version = match conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .max()
+    {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+
+    let migration = MIGRATOR
+        .iter()
+        .find(|migration| {
+            migration.version == version && migration.migration_type.is_down_migration()
+        })
+        .ok_or_else(|| anyhow!("down migration not found: {}", version))?;
+    let _ = conn.revert(migration).await?;
+    Ok(Some(version))
+}
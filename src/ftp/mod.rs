@@ -0,0 +1,94 @@
+mod session;
+
+use crate::media::{JobWorkers, MediaGenerateOption};
+use anyhow::Result;
+use session::Session;
+use sqlx::SqlitePool;
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{net::TcpListener, sync::watch};
+
+// アップロードされたファイルを置くディレクトリ、ユーザごとに分ける
+pub const FTP_DIRECTORY_NAME: &str = "ftp";
+
+/// カメラなどからアップロードを受け付ける FTP サーバ
+/// アップロードが終わったファイルはそのままジョブに追加する
+pub struct FtpServer {
+    pub pool: SqlitePool,
+    pub address: SocketAddr,
+    /// PASV で返すアドレス、指定しない場合は接続を受けたアドレス
+    pub passive_address: Option<IpAddr>,
+    /// PASV で使うポートの範囲
+    pub passive_ports: RangeInclusive<u16>,
+    /// `{root}/{user}` 以下にアップロードさせる
+    pub root: PathBuf,
+    pub jobs: JobWorkers,
+    /// 取り込むワーカーの数
+    pub workers: usize,
+}
+
+impl FtpServer {
+    pub fn new(
+        pool: SqlitePool,
+        data_dir: &Path,
+        address: SocketAddr,
+        passive_address: Option<IpAddr>,
+        passive_ports: RangeInclusive<u16>,
+        option: MediaGenerateOption,
+        workers: usize,
+    ) -> Result<Self> {
+        ensure!(!passive_ports.is_empty(), "invalid passive ports: {:?}", passive_ports);
+        Ok(FtpServer {
+            jobs: JobWorkers::new(pool.clone(), data_dir, option),
+            pool,
+            address,
+            passive_address,
+            passive_ports,
+            root: data_dir.join(FTP_DIRECTORY_NAME),
+            workers,
+        })
+    }
+
+    /// `shutdown` が `true` になったら接続の受け付けをやめ、取り込み中のものを終えてから止まる
+    pub async fn start(self, shutdown: watch::Receiver<bool>) -> Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+
+        let listener = TcpListener::bind(self.address).await?;
+        log::info!("ftp listening on {}", listener.local_addr()?);
+
+        let server = Arc::new(self);
+        let (accepted, worked) = tokio::join!(
+            server.clone().accept(listener, shutdown.clone()),
+            server.jobs.run(server.workers, shutdown)
+        );
+        accepted.and(worked)
+    }
+
+    async fn accept(
+        self: Arc<Self>,
+        listener: TcpListener,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        while !*shutdown.borrow() {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = accepted?;
+                    log::info!("ftp connected from {}", peer);
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        let result = async { Session::new(server, stream)?.run().await }.await;
+                        if let Err(e) = result {
+                            log::info!("ftp session {} closed: {:?}", peer, e);
+                        }
+                    });
+                }
+                _ = shutdown.changed() => {}
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+use super::FtpServer;
+use crate::{media::common::is_target, user::User};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::{
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+};
+
+// データ接続を待つ時間
+const DATA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// コマンドが来ないまま接続を切るまでの時間
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// コマンドの最大長
+const MAX_COMMAND_LENGTH: u64 = 4096;
+
+// ログインに失敗した時に待つ時間
+const LOGIN_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+// アップロード中のファイルにつける拡張子、書き込みが終わったら外す
+const PARTIAL_EXTENSION: &str = "part";
+
+/// 一つの制御接続
+pub struct Session {
+    server: Arc<FtpServer>,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    /// 接続を受けたアドレス
+    local_ip: IpAddr,
+    /// クライアントのアドレス、データ接続もここから来たものだけ受け付ける
+    peer_ip: IpAddr,
+    /// USER で受け取って PASS を待っているユーザ名
+    user_name: Option<String>,
+    /// ログインしたユーザのディレクトリ
+    home: Option<PathBuf>,
+    /// ホームからの相対パス
+    cwd: PathBuf,
+    /// PASV で開いたデータ接続の待ち受け
+    passive: Option<TcpListener>,
+    /// RNFR で指定したパス
+    rename_from: Option<PathBuf>,
+}
+
+impl Session {
+    pub fn new(server: Arc<FtpServer>, stream: TcpStream) -> Result<Self> {
+        let local_ip = stream.local_addr()?.ip();
+        let peer_ip = stream.peer_addr()?.ip();
+        let (reader, writer) = stream.into_split();
+        Ok(Session {
+            server,
+            reader: BufReader::new(reader),
+            writer,
+            local_ip,
+            peer_ip,
+            user_name: None,
+            home: None,
+            cwd: PathBuf::new(),
+            passive: None,
+            rename_from: None,
+        })
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        self.reply(220, "miruku ftp ready").await?;
+        loop {
+            let mut line = String::new();
+            let mut reader = (&mut self.reader).take(MAX_COMMAND_LENGTH);
+            let read = tokio::time::timeout(IDLE_TIMEOUT, reader.read_line(&mut line)).await??;
+            if read == 0 {
+                return Ok(());
+            }
+
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            let (command, arg) = match line.split_once(' ') {
+                Some((command, arg)) => (command.to_uppercase(), arg),
+                None => (line.to_uppercase(), ""),
+            };
+            if command == "PASS" {
+                log::debug!("ftp command: PASS ***");
+            } else {
+                log::debug!("ftp command: {} {}", command, arg);
+            }
+
+            if !self.handle(&command, arg).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// コマンドを処理する、接続を閉じる場合は `false` を返す
+    async fn handle(&mut self, command: &str, arg: &str) -> Result<bool> {
+        match command {
+            "USER" => {
+                self.user_name = Some(arg.to_owned());
+                self.home = None;
+                self.reply(331, "Please specify the password").await?;
+            }
+            "PASS" => self.login(arg).await?,
+            "QUIT" => {
+                self.reply(221, "Goodbye").await?;
+                return Ok(false);
+            }
+            "NOOP" => self.reply(200, "OK").await?,
+            "SYST" => self.reply(215, "UNIX Type: L8").await?,
+            "FEAT" => {
+                self.writer
+                    .write_all(b"211-Features:\r\n EPSV\r\n PASV\r\n SIZE\r\n MDTM\r\n UTF8\r\n211 End\r\n")
+                    .await?;
+            }
+            "OPTS" => self.reply(200, "OK").await?,
+            // ログインが必要なコマンド
+            _ if self.home.is_none() => self.reply(530, "Please login with USER and PASS").await?,
+            "PWD" | "XPWD" => {
+                let message = format!("\"/{}\" is the current directory", self.cwd.display());
+                self.reply(257, &message).await?;
+            }
+            "CWD" | "XCWD" => self.change_dir(arg).await?,
+            "CDUP" | "XCUP" => self.change_dir("..").await?,
+            "MKD" | "XMKD" => {
+                let path = self.resolve(arg);
+                match std::fs::create_dir_all(self.real_path(&path)) {
+                    Ok(_) => self.reply(257, &format!("\"/{}\" created", path.display())).await?,
+                    Err(e) => self.reply(550, &e.to_string()).await?,
+                }
+            }
+            "TYPE" => self.reply(200, "OK").await?,
+            "MODE" if arg.eq_ignore_ascii_case("S") => self.reply(200, "OK").await?,
+            "STRU" if arg.eq_ignore_ascii_case("F") => self.reply(200, "OK").await?,
+            "MODE" | "STRU" => self.reply(504, "Not supported").await?,
+            "PASV" => self.passive(false).await?,
+            "EPSV" => self.passive(true).await?,
+            "PORT" | "EPRT" => self.reply(502, "Active mode is not supported").await?,
+            "STOR" => self.store(arg).await?,
+            "LIST" | "NLST" => self.list(arg, command == "NLST").await?,
+            "SIZE" => {
+                let path = self.real_path(&self.resolve(arg));
+                match std::fs::metadata(path) {
+                    Ok(metadata) if metadata.is_file() => {
+                        self.reply(213, &metadata.len().to_string()).await?
+                    }
+                    _ => self.reply(550, "No such file").await?,
+                }
+            }
+            "MDTM" => {
+                let path = self.real_path(&self.resolve(arg));
+                match std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+                    Ok(modified) => {
+                        let modified = DateTime::<Utc>::from(modified);
+                        self.reply(213, &modified.format("%Y%m%d%H%M%S").to_string())
+                            .await?
+                    }
+                    _ => self.reply(550, "No such file").await?,
+                }
+            }
+            "RNFR" => {
+                let path = self.resolve(arg);
+                if self.real_path(&path).exists() {
+                    self.rename_from = Some(path);
+                    self.reply(350, "Ready for RNTO").await?;
+                } else {
+                    self.reply(550, "No such file").await?;
+                }
+            }
+            "RNTO" => self.rename(arg).await?,
+            // アップロード専用なので、取り出したり消したりはさせない
+            "RETR" | "APPE" | "DELE" | "RMD" | "XRMD" => {
+                self.reply(550, "Permission denied").await?
+            }
+            _ => self.reply(502, "Command not implemented").await?,
+        }
+        Ok(true)
+    }
+
+    async fn reply(&mut self, code: u32, message: &str) -> Result<()> {
+        let line = format!("{} {}\r\n", code, message);
+        self.writer.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn login(&mut self, password: &str) -> Result<()> {
+        let name = match self.user_name.take() {
+            Some(name) => name,
+            None => return self.reply(503, "Login with USER first").await,
+        };
+
+        let mut conn = self.server.pool.acquire().await?;
+        if User::authenticate(&mut conn, &name, password).await?.is_none() {
+            log::info!("ftp login failed: {}", name);
+            tokio::time::sleep(LOGIN_FAILURE_DELAY).await;
+            return self.reply(530, "Login incorrect").await;
+        }
+
+        let home = self.server.root.join(&name);
+        std::fs::create_dir_all(&home)?;
+        self.home = Some(home);
+        self.cwd = PathBuf::new();
+        log::info!("ftp logged in: {}", name);
+        self.reply(230, "Login successful").await
+    }
+
+    /// ホームからの相対パスにする、ホームより上には出られない
+    fn resolve(&self, arg: &str) -> PathBuf {
+        let mut path = if arg.starts_with('/') {
+            PathBuf::new()
+        } else {
+            self.cwd.clone()
+        };
+        for component in Path::new(arg).components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::ParentDir => {
+                    let _ = path.pop();
+                }
+                _ => {}
+            }
+        }
+        path
+    }
+
+    fn real_path(&self, path: &Path) -> PathBuf {
+        match &self.home {
+            Some(home) => home.join(path),
+            None => self.server.root.join(path),
+        }
+    }
+
+    async fn change_dir(&mut self, arg: &str) -> Result<()> {
+        let path = self.resolve(arg);
+        if self.real_path(&path).is_dir() {
+            self.cwd = path;
+            self.reply(250, "Directory successfully changed").await
+        } else {
+            self.reply(550, "Failed to change directory").await
+        }
+    }
+
+    /// 範囲内の空いているポートでデータ接続を待ち受ける
+    async fn passive(&mut self, extended: bool) -> Result<()> {
+        self.passive = None;
+        let mut listener = None;
+        for port in self.server.passive_ports.clone() {
+            // IPv6 で EPSV を使えるように、制御接続を受け付けたアドレスで待ち受ける
+            if let Ok(bound) = TcpListener::bind((self.local_ip, port)).await {
+                listener = Some((bound, port));
+                break;
+            }
+        }
+        let (listener, port) = match listener {
+            Some(listener) => listener,
+            None => return self.reply(425, "No passive ports available").await,
+        };
+        self.passive = Some(listener);
+
+        if extended {
+            return self
+                .reply(229, &format!("Entering Extended Passive Mode (|||{}|)", port))
+                .await;
+        }
+
+        let ip = match self.server.passive_address.unwrap_or(self.local_ip) {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => match ip.to_ipv4() {
+                Some(ip) => ip,
+                None => return self.reply(522, "Use EPSV with IPv6").await,
+            },
+        };
+        let [a, b, c, d] = ip.octets();
+        let message = format!(
+            "Entering Passive Mode ({},{},{},{},{},{})",
+            a,
+            b,
+            c,
+            d,
+            port >> 8,
+            port & 0xff
+        );
+        self.reply(227, &message).await
+    }
+
+    /// PASV で待ち受けているデータ接続を受け付ける
+    /// 他のアドレスからの接続はファイルを横取りされないように切る
+    async fn accept_data(&mut self) -> Result<Option<TcpStream>> {
+        let listener = match self.passive.take() {
+            Some(listener) => listener,
+            None => {
+                self.reply(425, "Use PASV or EPSV first").await?;
+                return Ok(None);
+            }
+        };
+        self.reply(150, "Opening data connection").await?;
+        let peer_ip = self.peer_ip.to_canonical();
+        let accept = async {
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                if peer.ip().to_canonical() == peer_ip {
+                    return Ok::<_, std::io::Error>(stream);
+                }
+                log::warn!(
+                    "ftp rejected data connection from {} (expected {})",
+                    peer,
+                    peer_ip
+                );
+            }
+        };
+        match tokio::time::timeout(DATA_CONNECTION_TIMEOUT, accept).await {
+            Ok(Ok(stream)) => Ok(Some(stream)),
+            _ => {
+                self.reply(425, "Failed to open data connection").await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// 一時的なファイルに書き込んでから名前を戻し、取り込むファイルであればジョブに追加する
+    async fn store(&mut self, arg: &str) -> Result<()> {
+        let path = self.real_path(&self.resolve(arg));
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name.to_string_lossy().to_string(),
+            None => return self.reply(553, "Invalid file name").await,
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // 取り込み済みのオリジナルを書き換えないように、既にあるファイルには書き込まない
+        if path.symlink_metadata().is_ok() {
+            return self.reply(553, "File already exists").await;
+        }
+        let partial = path.with_file_name(format!("{}.{}", file_name, PARTIAL_EXTENSION));
+
+        let mut data = match self.accept_data().await? {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        let written = async {
+            let mut file = File::create(&partial).await?;
+            let written = tokio::io::copy(&mut data, &mut file).await?;
+            file.sync_all().await?;
+            // 受け取っている間に同じ名前のファイルができていても上書きしない
+            tokio::fs::hard_link(&partial, &path).await?;
+            let _ = tokio::fs::remove_file(&partial).await;
+            Ok::<_, std::io::Error>(written)
+        }
+        .await;
+
+        let written = match written {
+            Ok(written) => written,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let _ = std::fs::remove_file(&partial);
+                return self.reply(553, "File already exists").await;
+            }
+            Err(e) => {
+                log::info!("ftp upload failed {:#?}: {:?}", path, e);
+                let _ = std::fs::remove_file(&partial);
+                return self.reply(426, "Transfer aborted").await;
+            }
+        };
+        log::info!("ftp uploaded {:#?} ({} bytes)", path, written);
+
+        self.enqueue(&path).await?;
+        self.reply(226, "Transfer complete").await
+    }
+
+    async fn rename(&mut self, arg: &str) -> Result<()> {
+        let from = match self.rename_from.take() {
+            Some(from) => self.real_path(&from),
+            None => return self.reply(503, "RNFR required first").await,
+        };
+        let to = self.real_path(&self.resolve(arg));
+        // `rename` は上書きしてしまうので、既にあるファイルには付け替えない
+        if to.symlink_metadata().is_ok() {
+            return self.reply(553, "File already exists").await;
+        }
+        if let Err(e) = std::fs::rename(&from, &to) {
+            return self.reply(550, &e.to_string()).await;
+        }
+        // 一時的な名前でアップロードしてから付け替える場合もある
+        self.enqueue(&to).await?;
+        self.reply(250, "Rename successful").await
+    }
+
+    async fn enqueue(&self, path: &Path) -> Result<()> {
+        if path.is_file() && is_target(path) {
+            self.server.jobs.enqueue(&[path.canonicalize()?]).await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&mut self, arg: &str, names_only: bool) -> Result<()> {
+        // `LIST -a` のようなオプションは無視する
+        let arg = if arg.starts_with('-') { "" } else { arg };
+        let path = self.real_path(&self.resolve(arg));
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries.flatten().collect::<Vec<_>>(),
+            Err(_) if path.is_file() => vec![],
+            Err(_) => return self.reply(550, "No such directory").await,
+        };
+
+        let mut listing = String::new();
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if names_only {
+                listing.push_str(&format!("{}\r\n", name));
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let modified = metadata
+                .modified()
+                .map(DateTime::<Utc>::from)
+                .unwrap_or_else(|_| Utc::now());
+            listing.push_str(&format!(
+                "{} 1 ftp ftp {:>12} {} {}\r\n",
+                if metadata.is_dir() { "drwxr-xr-x" } else { "-rw-r--r--" },
+                metadata.len(),
+                modified.format("%b %d %H:%M"),
+                name
+            ));
+        }
+
+        let mut data = match self.accept_data().await? {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        let _ = data.write_all(listing.as_bytes()).await;
+        let _ = data.shutdown().await;
+        self.reply(226, "Directory send OK").await
+    }
+}
@@ -4,22 +4,89 @@ extern crate anyhow;
 use anyhow::Result;
 use chrono::LocalResult;
 use clap::Parser;
+use config::Config;
 use std::path::PathBuf;
 
+mod config;
+mod db;
+mod ftp;
 mod media;
 mod server;
+mod user;
+mod watcher;
 
-const DEFAULT_DATA_DIR: &str = "./data";
-const DEFAULT_SERVER_PORT: &str = "9999";
+const DEFAULT_JOBS_COUNT: &str = "100";
+
+#[derive(Parser, Debug)]
+struct DatabaseArgs {
+    /// コネクションプールの最大接続数 (database.pool_size)
+    #[clap(long)]
+    pool_size: Option<u32>,
+
+    /// データベースがロックされている場合に待つミリ秒 (database.busy_timeout)
+    #[clap(long)]
+    busy_timeout: Option<u64>,
+}
+
+impl DatabaseArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(pool_size) = self.pool_size {
+            config.database.pool_size = pool_size;
+        }
+        if let Some(busy_timeout) = self.busy_timeout {
+            config.database.busy_timeout = busy_timeout;
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+struct ScanArgs {
+    /// ディレクトリを探す最大の深さ、`0` の場合は無制限 (scan.max_depth)
+    #[clap(long)]
+    max_depth: Option<u32>,
+
+    /// 一致するファイルだけを取り込む、複数指定できる (scan.include)
+    #[clap(long, multiple_occurrences = true)]
+    include: Vec<String>,
+
+    /// 一致するファイルとディレクトリを除く、複数指定できる (scan.exclude に加える)
+    #[clap(long, multiple_occurrences = true)]
+    exclude: Vec<String>,
+}
+
+impl ScanArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(max_depth) = self.max_depth {
+            config.scan.max_depth = max_depth;
+        }
+        if !self.include.is_empty() {
+            config.scan.include = self.include.clone();
+        }
+        config.scan.exclude.extend(self.exclude.iter().cloned());
+    }
+}
 
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct StartServerSubcommand {
-    #[clap(default_value = DEFAULT_DATA_DIR)]
-    data_dir: String,
+    data_dir: Option<PathBuf>,
+
+    /// 待ち受けるポート (server.port)
+    port: Option<u16>,
+
+    /// このディレクトリを監視して、追加されたファイルからメディアを生成する
+    #[clap(long)]
+    watch: Option<String>,
+
+    /// 監視している場合に取り込むワーカーの数 (ingest.workers)
+    #[clap(long)]
+    workers: Option<usize>,
+
+    #[clap(flatten)]
+    scan: ScanArgs,
 
-    #[clap(default_value = DEFAULT_SERVER_PORT)]
-    port: u64,
+    #[clap(flatten)]
+    database: DatabaseArgs,
 }
 
 #[derive(Parser, Debug)]
@@ -27,11 +94,303 @@ struct StartServerSubcommand {
 struct GenerateMediaSubcommand {
     origin: String,
 
-    #[clap(default_value = DEFAULT_DATA_DIR)]
-    dest: String,
+    dest: Option<PathBuf>,
 
     #[clap(short = 'w')]
     watch: bool,
+
+    /// 取り込むワーカーの数 (ingest.workers)
+    #[clap(long)]
+    workers: Option<usize>,
+
+    #[clap(flatten)]
+    scan: ScanArgs,
+
+    #[clap(flatten)]
+    database: DatabaseArgs,
+}
+
+#[derive(Parser, Debug)]
+struct ImportTakeoutSubcommand {
+    /// 展開した Google Takeout か Apple Photos から書き出したディレクトリ
+    origin: PathBuf,
+
+    data_dir: Option<PathBuf>,
+
+    /// 取り込むワーカーの数 (ingest.workers)
+    #[clap(long)]
+    workers: Option<usize>,
+
+    #[clap(flatten)]
+    scan: ScanArgs,
+
+    #[clap(flatten)]
+    database: DatabaseArgs,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct StartFtpSubcommand {
+    data_dir: Option<PathBuf>,
+
+    /// 待ち受けるポート (ftp.port)
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// PASV で返すアドレス、NAT の内側で動かす場合に指定する (ftp.pasv_address)
+    #[clap(long)]
+    pasv_address: Option<std::net::IpAddr>,
+
+    /// PASV で待ち受けるポートの範囲の最小 (ftp.pasv_min_port)
+    #[clap(long)]
+    pasv_min_port: Option<u16>,
+
+    /// PASV で待ち受けるポートの範囲の最大 (ftp.pasv_max_port)
+    #[clap(long)]
+    pasv_max_port: Option<u16>,
+
+    /// 取り込むワーカーの数 (ingest.workers)
+    #[clap(long)]
+    workers: Option<usize>,
+
+    #[clap(flatten)]
+    database: DatabaseArgs,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct SetVisibilitySubcommand {
+    /// `<media_id> <visibility>` か、`--date-range`/`--dir` と `<visibility>` を指定する
+    #[clap(min_values = 1, max_values = 2, value_name = "MEDIA_ID> <VISIBILITY")]
+    args: Vec<String>,
+
+    /// 対象の日付の範囲 (例: `2022-01-01..2022-01-31`)
+    #[clap(long, conflicts_with = "dir")]
+    date_range: Option<String>,
+
+    /// オリジナルのファイルがこのディレクトリ以下にあるものを対象にする
+    #[clap(long)]
+    dir: Option<String>,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct SetAttrSubcommand {
+    media_id: String,
+
+    /// `key=value` の形式で指定する
+    #[clap(required = true)]
+    attributes: Vec<String>,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct ShowSubcommand {
+    media_id: String,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct SetValueSubcommand {
+    media_id: String,
+
+    value: String,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct TrashSubcommand {
+    #[clap(required = true)]
+    media_ids: Vec<String>,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct PurgeSubcommand {
+    /// ゴミ箱に入れてからこの日数が経過したものを削除する (trash.retention_days)
+    #[clap(long)]
+    retention_days: Option<i64>,
+
+    /// オリジナルのファイルも削除する
+    #[clap(long)]
+    remove_origin: bool,
+
+    /// 削除せずに対象を表示するだけにする
+    #[clap(long)]
+    dry_run: bool,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct ReindexSubcommand {
+    /// 抽出し直すフィールドをカンマ区切りで指定する (date,exif,gps,dimensions)、デフォルトは全て
+    #[clap(long)]
+    fields: Option<String>,
+
+    /// 更新せずに変更点を表示するだけにする
+    #[clap(long)]
+    dry_run: bool,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct MigrateSubcommand {
+    /// 適用せずにマイグレーションごとの状況を表示する
+    #[clap(long, conflicts_with = "down")]
+    status: bool,
+
+    /// 最後に適用したマイグレーションを一つ戻す
+    #[clap(long)]
+    down: bool,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct DataMigrateSubcommand {
+    /// 適用せずにデータマイグレーションごとの状況を表示する
+    #[clap(long, conflicts_with_all = &["dry-run", "mark-applied"])]
+    status: bool,
+
+    /// 変更内容を表示するだけでロールバックする
+    #[clap(long, conflicts_with = "mark-applied")]
+    dry_run: bool,
+
+    /// データを変更せずに適用済みとして記録する
+    #[clap(long)]
+    mark_applied: bool,
+
+    /// 指定した場合だけ適用するデータマイグレーションのバージョン、複数指定できる
+    #[clap(long, multiple_occurrences = true)]
+    include: Vec<i64>,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct JobsListSubcommand {
+    /// この状態のジョブだけを表示する (queued,running,done,failed)
+    #[clap(long)]
+    status: Option<media::JobStatus>,
+
+    #[clap(long, default_value = DEFAULT_JOBS_COUNT)]
+    count: u64,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct JobsRetrySubcommand {
+    /// やり直すジョブ、指定しない場合は失敗したもの全て
+    job_ids: Vec<i64>,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct JobsClearSubcommand {
+    /// 削除するジョブの状態
+    #[clap(long, default_value = "done")]
+    status: media::JobStatus,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+enum JobsSubcommand {
+    /// ジョブの一覧を表示する
+    #[clap(name = "list")]
+    List(JobsListSubcommand),
+
+    /// 失敗したジョブをやり直す、ワーカーが動いていれば取り込まれる
+    #[clap(name = "retry")]
+    Retry(JobsRetrySubcommand),
+
+    /// 終わったジョブを削除する
+    #[clap(name = "clear")]
+    Clear(JobsClearSubcommand),
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct UserAddSubcommand {
+    name: String,
+
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct UserListSubcommand {
+    #[clap(short = 'd', long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+enum UserSubcommand {
+    /// ユーザを追加する、パスワードは標準入力から読み込む
+    #[clap(name = "add")]
+    Add(UserAddSubcommand),
+
+    /// ユーザの一覧を表示する
+    #[clap(name = "list")]
+    List(UserListSubcommand),
+
+    /// ユーザを削除する
+    #[clap(name = "remove")]
+    Remove(UserAddSubcommand),
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+enum ConfigSubcommand {
+    /// 設定ファイル、環境変数、デフォルトを合わせた設定を表示する
+    #[clap(name = "show")]
+    Show,
+}
+
+#[derive(Parser, Debug)]
+#[clap(about, version, author)]
+struct Cli {
+    /// 設定ファイル、指定しない場合は `./miruku.toml` があれば読み込む
+    #[clap(short = 'c', long, global = true, env = "MIRUKU_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    app: App,
 }
 
 #[derive(Parser, Debug)]
@@ -43,29 +402,205 @@ enum App {
     #[clap(name = "generate-media")]
     GenerateMedia(GenerateMediaSubcommand),
 
-    /// データベースに記録した時刻を Local に直す
-    #[clap(name = "fix-date")]
-    FixDate { database_path: String },
+    /// 書き出した写真を、添えられた JSON の撮影日時や位置情報、アルバムと一緒に取り込む
+    #[clap(name = "import-takeout")]
+    ImportTakeout(ImportTakeoutSubcommand),
+
+    /// アップロードされたファイルを取り込む FTP サーバを開始する
+    #[clap(name = "start-ftp")]
+    StartFtp(StartFtpSubcommand),
+
+    /// メディアの公開範囲を変更する
+    #[clap(name = "set-visibility")]
+    SetVisibility(SetVisibilitySubcommand),
+
+    /// メディアに属性を設定する
+    #[clap(name = "set-attr")]
+    SetAttr(SetAttrSubcommand),
+
+    /// メディアのレーティングを 0 から 5 で設定する
+    #[clap(name = "set-rating")]
+    SetRating(SetValueSubcommand),
+
+    /// メディアのお気に入りを true か false で設定する
+    #[clap(name = "set-favorite")]
+    SetFavorite(SetValueSubcommand),
+
+    /// メディアのカラーラベルを設定する、`none` でラベルを外す
+    #[clap(name = "set-label")]
+    SetLabel(SetValueSubcommand),
+
+    /// メディアのタイトルを設定する、空文字で消す
+    #[clap(name = "set-title")]
+    SetTitle(SetValueSubcommand),
+
+    /// メディアの説明を設定する、空文字で消す
+    #[clap(name = "set-description")]
+    SetDescription(SetValueSubcommand),
+
+    /// メディアのメタ情報を表示する
+    #[clap(name = "show")]
+    Show(ShowSubcommand),
+
+    /// メディアをゴミ箱に入れる
+    #[clap(name = "delete")]
+    Delete(TrashSubcommand),
+
+    /// ゴミ箱に入れたメディアを元に戻す
+    #[clap(name = "restore")]
+    Restore(TrashSubcommand),
+
+    /// ゴミ箱に入れてから一定期間が経過したメディアを完全に削除する
+    #[clap(name = "purge")]
+    Purge(PurgeSubcommand),
+
+    /// オリジナルからメタ情報を抽出し直す
+    #[clap(name = "reindex")]
+    Reindex(ReindexSubcommand),
+
+    /// データベースのマイグレーションを適用する
+    #[clap(name = "migrate")]
+    Migrate(MigrateSubcommand),
+
+    /// 既存のデータを直すデータマイグレーションを適用する
+    #[clap(name = "data-migrate")]
+    DataMigrate(DataMigrateSubcommand),
+
+    /// 取り込みジョブを操作する
+    #[clap(name = "jobs", subcommand)]
+    Jobs(JobsSubcommand),
+
+    /// FTP でアップロードできるユーザを管理する
+    #[clap(name = "user", subcommand)]
+    User(UserSubcommand),
+
+    /// 設定を確認する
+    #[clap(name = "config", subcommand)]
+    Config(ConfigSubcommand),
+}
+
+impl App {
+    /// コマンドライン引数で指定したものを設定に反映する
+    fn apply(&self, config: &mut Config) {
+        let data_dir = match self {
+            App::StartServer(s) => {
+                if let Some(port) = s.port {
+                    config.server.port = port;
+                }
+                if let Some(workers) = s.workers {
+                    config.ingest.workers = workers;
+                }
+                s.scan.apply(config);
+                s.database.apply(config);
+                &s.data_dir
+            }
+            App::GenerateMedia(s) => {
+                if let Some(workers) = s.workers {
+                    config.ingest.workers = workers;
+                }
+                s.scan.apply(config);
+                s.database.apply(config);
+                &s.dest
+            }
+            App::ImportTakeout(s) => {
+                if let Some(workers) = s.workers {
+                    config.ingest.workers = workers;
+                }
+                s.scan.apply(config);
+                s.database.apply(config);
+                &s.data_dir
+            }
+            App::StartFtp(s) => {
+                if let Some(port) = s.port {
+                    config.ftp.port = port;
+                }
+                if let Some(pasv_address) = s.pasv_address {
+                    config.ftp.pasv_address = Some(pasv_address);
+                }
+                if let Some(pasv_min_port) = s.pasv_min_port {
+                    config.ftp.pasv_min_port = pasv_min_port;
+                }
+                if let Some(pasv_max_port) = s.pasv_max_port {
+                    config.ftp.pasv_max_port = pasv_max_port;
+                }
+                if let Some(workers) = s.workers {
+                    config.ingest.workers = workers;
+                }
+                s.database.apply(config);
+                &s.data_dir
+            }
+            App::SetVisibility(s) => &s.data_dir,
+            App::SetAttr(s) => &s.data_dir,
+            App::SetRating(s)
+            | App::SetFavorite(s)
+            | App::SetLabel(s)
+            | App::SetTitle(s)
+            | App::SetDescription(s) => &s.data_dir,
+            App::Show(s) => &s.data_dir,
+            App::Delete(s) | App::Restore(s) => &s.data_dir,
+            App::Purge(s) => {
+                if let Some(retention_days) = s.retention_days {
+                    config.trash.retention_days = retention_days;
+                }
+                &s.data_dir
+            }
+            App::Reindex(s) => &s.data_dir,
+            App::Migrate(s) => &s.data_dir,
+            App::DataMigrate(s) => &s.data_dir,
+            App::Jobs(JobsSubcommand::List(s)) => &s.data_dir,
+            App::Jobs(JobsSubcommand::Retry(s)) => &s.data_dir,
+            App::Jobs(JobsSubcommand::Clear(s)) => &s.data_dir,
+            App::User(UserSubcommand::Add(s) | UserSubcommand::Remove(s)) => &s.data_dir,
+            App::User(UserSubcommand::List(s)) => &s.data_dir,
+            App::Config(_) => &None,
+        };
+        if let Some(data_dir) = data_dir {
+            config.data_dir = data_dir.to_owned();
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = App::parse();
+    let Cli { config, app: args } = Cli::parse();
     log::debug!("{:#?}", args);
 
     env_logger::init();
 
+    let mut config = Config::load(config.as_deref())?;
+    args.apply(&mut config);
+    config.validate()?;
+    log::debug!("{:#?}", config);
+
     match args {
         App::StartServer(s) => {
             use server::*;
             use std::path::Path;
 
+            let option = config.generate_option()?;
+            let pool = config.create_pool().await?;
+
+            let watcher = match &s.watch {
+                Some(origin) => Some(watcher::Watcher::new(
+                    pool.clone(),
+                    Path::new(origin),
+                    &config.data_dir,
+                    option.clone(),
+                    config.ingest.workers,
+                )?),
+                None => None,
+            };
+
             let server = Server {
-                data_dir: Path::new(&s.data_dir),
-                port: s.port,
+                data_dir: &config.data_dir,
+                address: (config.server.address, config.server.port).into(),
+                front_dir: &config.server.front_dir,
+                pool,
+                option,
+                watcher,
             };
 
-            let _ = server.start().await?;
+            server.start().await?;
 
             Ok(())
         }
@@ -73,112 +608,511 @@ async fn main() -> Result<()> {
             use media::*;
             use std::path::Path;
 
-            // イベントのパスからMediaを生成する
-            async fn from_event_path(origin: PathBuf, dest: &Path, option: &MediaGenerateOption) {
-                if !media::common::is_target(&origin) {
-                    return;
-                }
-                if let Ok(origin) = origin.canonicalize() {
-                    log::info!("start origin ({:#?})", origin);
-                    match Media::generate(&origin, dest, option).await {
-                        Ok(media) => log::info!("{:#?}: OK", media.meta.origin),
-                        Err(e) => log::info!("{:#?}: {:?}", origin, e),
-                    }
-                }
-            }
-
             let origin = Path::new(&s.origin);
-            let dest = Path::new(&s.dest);
+            let dest = config.data_dir.as_path();
+            let workers = config.ingest.workers;
+
+            let option = config.generate_option()?;
 
-            let option = MediaGenerateOption {};
+            // 監視中も同じプールを使い回す
+            let pool = config.create_pool().await?;
 
             if s.watch {
-                use notify::{
-                    watcher,
-                    DebouncedEvent::{Create, Write},
-                    RecursiveMode, Watcher,
-                };
-                use std::sync::mpsc::channel;
-                use std::time::Duration;
-
-                let (tx, rx) = channel();
-
-                let mut watcher = watcher(tx, Duration::from_secs(5))?;
-                watcher.watch(&origin, RecursiveMode::Recursive)?;
-
-                loop {
-                    match rx.recv() {
-                        Ok(Write(origin)) => from_event_path(origin, dest, &option).await,
-                        Ok(Create(origin)) => from_event_path(origin, dest, &option).await,
-                        Ok(_event) => {}
-                        Err(e) => log::debug!("watch error: {:?}", e),
+                use watcher::Watcher;
+
+                // 停止していた間に追加されたものを取り込んでから監視する
+                let watcher = Watcher::new(pool, origin, dest, option, workers)?;
+
+                // Ctrl-C で取り込み中のものを終えてから止める
+                let (shutdown, shutdown_rx) = tokio::sync::watch::channel(false);
+                tokio::spawn(async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        let _ = shutdown.send(true);
                     }
-                }
+                });
+                return watcher.run(shutdown_rx).await;
             }
 
             if origin.is_dir() {
-                let medias = Media::generate_many(origin, dest, &option).await?;
-
-                log::debug!("{:#?}", medias);
+                let jobs = Media::generate_many(&pool, origin, dest, &option, workers).await?;
+                print_jobs(&jobs);
 
                 return Ok(());
             }
 
-            let media = Media::generate(origin, dest, &option).await?;
+            let media = Media::generate(&pool, origin, dest, &option).await?;
 
             log::debug!("{:#?}", media);
 
             Ok(())
         }
-        App::FixDate { database_path } => fix_date(&database_path).await,
+        App::ImportTakeout(s) => import_takeout(s, &config).await,
+        App::StartFtp(_) => start_ftp(&config).await,
+        App::SetVisibility(s) => set_visibility(s, &config).await,
+        App::SetAttr(s) => set_attr(s, &config).await,
+        App::SetRating(s) => set_rating(s, &config).await,
+        App::SetFavorite(s) => set_favorite(s, &config).await,
+        App::SetLabel(s) => set_label(s, &config).await,
+        App::SetTitle(s) => set_title(s, &config).await,
+        App::SetDescription(s) => set_description(s, &config).await,
+        App::Show(s) => show(s, &config).await,
+        App::Delete(s) => trash(s, true, &config).await,
+        App::Restore(s) => trash(s, false, &config).await,
+        App::Purge(s) => purge(s, &config).await,
+        App::Reindex(s) => reindex(s, &config).await,
+        App::Migrate(s) => migrate(s, &config).await,
+        App::DataMigrate(s) => data_migrate(s, &config).await,
+        App::Jobs(s) => jobs(s, &config).await,
+        App::User(s) => user(s, &config).await,
+        App::Config(ConfigSubcommand::Show) => {
+            if let Some(source) = &config.source {
+                println!("# {}", source.display());
+            }
+            print!("{}", toml::to_string_pretty(&config)?);
+            Ok(())
+        }
     }
 }
 
-async fn fix_date(data_dir: &str) -> Result<()> {
-    use chrono::prelude::*;
-    use media::*;
-    use sqlx::{prelude::*, query_as, SqliteConnection};
+/// ジョブの結果を表示する
+fn print_jobs(jobs: &[media::Job]) {
+    use media::JobStatus;
+
+    log::debug!("{:#?}", jobs);
 
-    #[derive(FromRow, Debug, Clone)]
-    struct MediaIdWithDateRow {
-        pub media_id: MediaId,
-        pub date: NaiveDateTime,
+    let failed = jobs
+        .iter()
+        .filter(|job| job.status == JobStatus::Failed)
+        .collect::<Vec<_>>();
+    for job in &failed {
+        println!(
+            "{} {}: {}",
+            job.job_id,
+            job.origin,
+            job.last_error.as_deref().unwrap_or_default()
+        );
     }
+    println!(
+        "{} done, {} failed",
+        jobs.len() - failed.len(),
+        failed.len()
+    );
+}
 
-    let mut conn = SqliteConnection::connect(data_dir).await?;
+async fn import_takeout(s: ImportTakeoutSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    ensure!(s.origin.is_dir(), "not a directory: {}", s.origin.display());
+
+    let option = MediaGenerateOption {
+        sidecar: true,
+        ..config.generate_option()?
+    };
+    let pool = config.create_pool().await?;
 
-    let medias: Vec<MediaIdWithDateRow> = query_as(
-        r#"
-        select media_id, date, visibility from metas
-        order by date desc
-        "#,
+    let jobs = Media::generate_many(
+        &pool,
+        &s.origin,
+        &config.data_dir,
+        &option,
+        config.ingest.workers,
     )
-    .fetch_all(&mut conn)
     .await?;
-    let medias_count = medias.len();
-    let medias = medias
-        .into_iter()
-        .flat_map(|MediaIdWithDateRow { media_id, date }| {
-            // date が Local のまま Utc のものとして放り込まれているので、Local -> Utc に変換してアップデートする
-            match Local.from_local_datetime(&date) {
-                LocalResult::<_>::Single(date) => (media_id, date.naive_utc()).into(),
-                _ => None,
-            }
+    print_jobs(&jobs);
+
+    Ok(())
+}
+
+async fn start_ftp(config: &Config) -> Result<()> {
+    let pool = config.create_pool().await?;
+
+    let ftp = &config.ftp;
+    let server = ftp::FtpServer::new(
+        pool,
+        &config.data_dir,
+        (ftp.address, ftp.port).into(),
+        ftp.pasv_address,
+        ftp.pasv_min_port..=ftp.pasv_max_port,
+        config.generate_option()?,
+        config.ingest.workers,
+    )?;
+
+    // Ctrl-C で取り込み中のものを終えてから止める
+    let (shutdown, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = shutdown.send(true);
+        }
+    });
+    server.start(shutdown_rx).await
+}
+
+async fn set_visibility(s: SetVisibilitySubcommand, config: &Config) -> Result<()> {
+    use media::*;
+    use std::path::Path;
+
+    let (media_id, visibility) = match s.args.as_slice() {
+        [media_id, visibility] => (Some(media_id), visibility),
+        [visibility] => (None, visibility),
+        _ => bail!("invalid arguments"),
+    };
+    let visibility: MediaVisibility = visibility.parse()?;
+
+    let mut conn = config.connect().await?;
+
+    let metas = match (media_id, &s.date_range, &s.dir) {
+        (Some(media_id), None, None) => vec![MediaMeta::open(&mut conn, media_id).await?],
+        (None, Some(date_range), None) => {
+            let (from, to) = parse_date_range(date_range)?;
+            MediaMeta::list_by_date_range(&mut conn, from, to).await?
+        }
+        (None, None, Some(dir)) => MediaMeta::list_by_origin_dir(&mut conn, Path::new(dir)).await?,
+        _ => bail!("specify exactly one of <media_id>, --date-range or --dir"),
+    };
+
+    let count = metas.len();
+    for mut meta in metas {
+        meta.update_visibility(&mut conn, visibility).await?;
+        log::info!("{}: {:?}", *meta.media_id, visibility);
+    }
+    println!("{} media updated", count);
+
+    Ok(())
+}
+
+/// `2022-01-01..2022-01-31` の形式の日付の範囲をパースする
+/// 日付は Local として扱い、終わりの日を含む UTC の範囲 (`from` 以上 `to` 未満) を返す
+fn parse_date_range(range: &str) -> Result<(chrono::NaiveDateTime, chrono::NaiveDateTime)> {
+    use chrono::prelude::*;
+
+    fn to_utc(date: NaiveDate) -> Result<NaiveDateTime> {
+        match Local.from_local_datetime(&date.and_hms(0, 0, 0)) {
+            LocalResult::Single(date) => Ok(date.naive_utc()),
+            _ => bail!("invalid local date: {}", date),
+        }
+    }
+
+    let (from, to) = match range.split_once("..") {
+        Some(range) => range,
+        None => bail!("date range must be `YYYY-MM-DD..YYYY-MM-DD`: {}", range),
+    };
+    let from = NaiveDate::parse_from_str(from, "%Y-%m-%d")?;
+    let to = NaiveDate::parse_from_str(to, "%Y-%m-%d")?.succ();
+    ensure!(from < to, "empty date range: {}", range);
+
+    Ok((to_utc(from)?, to_utc(to)?))
+}
+
+async fn set_attr(s: SetAttrSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    let attributes = s
+        .attributes
+        .iter()
+        .map(|attribute| match attribute.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(anyhow!("attribute must be `key=value`: {}", attribute)),
         })
-        .collect::<Vec<_>>();
-    ensure!(medias.len() == medias_count);
-    
-    for (media_id, date) in medias {
-        const QUERY: &str = r#"
-            update metas
-            set date = ?
-            where media_id = ?
-        "#;
-        let _ = sqlx::query(QUERY)
-            .bind(date)
-            .bind(media_id)
-            .execute(&mut conn)
-            .await?;
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut conn = config.connect().await?;
+    let mut meta = MediaMeta::open(&mut conn, &s.media_id).await?;
+
+    for (key, value) in attributes {
+        meta.set_attribute(&mut conn, key, value).await?;
+    }
+
+    Ok(())
+}
+
+async fn set_rating(s: SetValueSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    let rating: u8 = s.value.parse()?;
+
+    let mut conn = config.connect().await?;
+    let mut meta = MediaMeta::open(&mut conn, &s.media_id).await?;
+    meta.update_rating(&mut conn, rating).await
+}
+
+async fn set_favorite(s: SetValueSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    let favorite: bool = s.value.parse()?;
+
+    let mut conn = config.connect().await?;
+    let mut meta = MediaMeta::open(&mut conn, &s.media_id).await?;
+    meta.update_favorite(&mut conn, favorite).await
+}
+
+async fn set_label(s: SetValueSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    let label: Option<ColorLabel> = match s.value.as_str() {
+        "none" => None,
+        label => Some(label.parse()?),
+    };
+
+    let mut conn = config.connect().await?;
+    let mut meta = MediaMeta::open(&mut conn, &s.media_id).await?;
+    meta.update_label(&mut conn, label).await
+}
+
+async fn set_title(s: SetValueSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    let title = Some(s.value).filter(|title| !title.is_empty());
+
+    let mut conn = config.connect().await?;
+    let mut meta = MediaMeta::open(&mut conn, &s.media_id).await?;
+    meta.update_title(&mut conn, title).await
+}
+
+async fn set_description(s: SetValueSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    let description = Some(s.value).filter(|description| !description.is_empty());
+
+    let mut conn = config.connect().await?;
+    let mut meta = MediaMeta::open(&mut conn, &s.media_id).await?;
+    meta.update_description(&mut conn, description).await
+}
+
+async fn show(s: ShowSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    let mut conn = config.connect().await?;
+    let meta = MediaMeta::open(&mut conn, &s.media_id).await?;
+    let tags = Tag::list_by_media(&mut conn, &s.media_id).await?;
+
+    // タグは metas の外にあるので一緒に表示する
+    let mut json = serde_json::to_value(&meta)?;
+    json["tags"] = serde_json::to_value(tags)?;
+
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
+
+/// `delete` が `true` の場合はゴミ箱に入れ、`false` の場合はゴミ箱から戻す
+async fn trash(s: TrashSubcommand, delete: bool, config: &Config) -> Result<()> {
+    use media::*;
+
+    let mut conn = config.connect().await?;
+
+    for media_id in &s.media_ids {
+        let mut meta = MediaMeta::open(&mut conn, media_id).await?;
+        if delete {
+            meta.trash(&mut conn).await?;
+        } else {
+            meta.restore(&mut conn).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn purge(s: PurgeSubcommand, config: &Config) -> Result<()> {
+    use chrono::{Duration, Utc};
+    use media::*;
+
+    let mut conn = config.connect().await?;
+
+    let before = Utc::now().naive_utc() - Duration::days(config.trash.retention_days);
+    let metas = MediaMeta::list_trashed_before(&mut conn, before).await?;
+
+    for meta in metas {
+        println!("{}: {}", *meta.media_id, meta.origin);
+        if s.dry_run {
+            continue;
+        }
+        let media: Media = meta.into();
+        media.purge(&mut conn, &config.data_dir, s.remove_origin).await?;
+    }
+
+    Ok(())
+}
+
+async fn reindex(s: ReindexSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+    use sqlx::Connection;
+
+    let fields = match &s.fields {
+        Some(fields) => fields
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<ReindexField>>>()?,
+        None => ReindexField::ALL.to_vec(),
+    };
+
+    let mut conn = config.connect().await?;
+
+    // オリジナルを読んでいる間はロックしないように、先に全て抽出する
+    let mut metas = vec![];
+    for meta in MediaMeta::list_all(&mut conn).await? {
+        let (reindexed, changes) = match meta.reindexed(&fields).await {
+            Ok(reindexed) => reindexed,
+            Err(e) => {
+                log::warn!("{}: {:?}", *meta.media_id, e);
+                continue;
+            }
+        };
+        if changes.is_empty() {
+            continue;
+        }
+        println!("{}: {}", *meta.media_id, meta.origin);
+        for change in changes {
+            println!("    {}: {} -> {}", change.column, change.before, change.after);
+        }
+        metas.push(reindexed);
+    }
+
+    println!("{} changed", metas.len());
+    if s.dry_run {
+        return Ok(());
+    }
+
+    let mut tx = Connection::begin(&mut conn).await?;
+    for meta in &metas {
+        meta.update_extracted(&mut tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn migrate(s: MigrateSubcommand, config: &Config) -> Result<()> {
+    if s.status {
+        let mut conn = config.connect_without_migrations().await?;
+        for status in db::migration_status(&mut conn).await? {
+            let state = match (status.applied, status.mismatched) {
+                (true, true) => "mismatched",
+                (true, false) => "applied",
+                (false, _) => "pending",
+            };
+            println!("{} {}: {}", status.version, status.description, state);
+        }
+        return Ok(());
+    }
+
+    if s.down {
+        let mut conn = config.connect_without_migrations().await?;
+        match db::revert_last_migration(&mut conn).await? {
+            Some(version) => println!("reverted {}", version),
+            None => println!("no migrations to revert"),
+        }
+        return Ok(());
+    }
+
+    // プールを作る際に適用される
+    let _ = config.create_pool().await?;
+
+    Ok(())
+}
+
+async fn data_migrate(s: DataMigrateSubcommand, config: &Config) -> Result<()> {
+    let mut conn = config.connect().await?;
+
+    if s.status {
+        for status in db::data_migration_status(&mut conn).await? {
+            let state = match status.applied_at {
+                Some(applied_at) => format!("applied at {}", applied_at),
+                None if status.migration.opt_in() => {
+                    format!("opt-in (--include {})", status.migration.version())
+                }
+                None => "pending".to_string(),
+            };
+            println!(
+                "{} {}: {}",
+                status.migration.version(),
+                status.migration.description(),
+                state
+            );
+        }
+        return Ok(());
+    }
+
+    if s.mark_applied {
+        for migration in db::mark_data_migrations_applied(&mut conn).await? {
+            println!("marked {} {}", migration.version(), migration.description());
+        }
+        return Ok(());
+    }
+
+    for (migration, changes) in db::run_data_migrations(&mut conn, &s.include, s.dry_run).await? {
+        println!("{} {}:", migration.version(), migration.description());
+        for change in changes {
+            println!("    {}", change);
+        }
+    }
+    if s.dry_run {
+        println!("dry run, rolled back");
+    }
+
+    Ok(())
+}
+
+async fn jobs(s: JobsSubcommand, config: &Config) -> Result<()> {
+    use media::*;
+
+    match s {
+        JobsSubcommand::List(s) => {
+            let mut conn = config.connect().await?;
+            for job in Job::list(&mut conn, s.status, s.count).await? {
+                println!(
+                    "{} {:?} attempts={} updated_at={} {}",
+                    job.job_id, job.status, job.attempts, job.updated_at, job.origin
+                );
+                if let Some(error) = job.last_error {
+                    println!("    {}", error);
+                }
+            }
+        }
+        JobsSubcommand::Retry(s) => {
+            let mut conn = config.connect().await?;
+            let retried = Job::retry(&mut conn, &s.job_ids).await?;
+            println!("retried {} jobs", retried);
+        }
+        JobsSubcommand::Clear(s) => {
+            let mut conn = config.connect().await?;
+            let cleared = Job::clear(&mut conn, s.status).await?;
+            println!("cleared {} jobs", cleared);
+        }
+    }
+
+    Ok(())
+}
+
+async fn user(s: UserSubcommand, config: &Config) -> Result<()> {
+    use user::User;
+
+    match s {
+        UserSubcommand::Add(s) => {
+            let mut conn = config.connect().await?;
+
+            eprint!("password: ");
+            let mut password = String::new();
+            let _ = std::io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(&['\r', '\n'][..]);
+            ensure!(!password.is_empty(), "empty password");
+
+            let user = User::create(&mut conn, &s.name, password).await?;
+            println!("added {}", user.name);
+        }
+        UserSubcommand::List(_) => {
+            let mut conn = config.connect().await?;
+            for user in User::list(&mut conn).await? {
+                println!("{} created_at={}", user.name, user.created_at);
+            }
+        }
+        UserSubcommand::Remove(s) => {
+            let mut conn = config.connect().await?;
+            if User::delete(&mut conn, &s.name).await? {
+                println!("removed {}", s.name);
+            } else {
+                println!("no such user: {}", s.name);
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,870 @@
+use super::meta::{Color, ColorLabel, MediaId, MediaMetaWithTags, MediaVisibility, TAGS_COLUMN};
+use super::search::{quote_term, split_terms, MIN_MATCH_LENGTH};
+use anyhow::Result;
+use chrono::{prelude::*, LocalResult};
+use serde::Deserialize;
+use sqlx::{
+    prelude::*,
+    query_as_with,
+    sqlite::{SqliteArguments, SqliteRow},
+    Arguments, Sqlite, SqliteConnection,
+};
+
+/// 複数のタグを指定した場合の条件
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMode {
+    /// 全てのタグが付いているもの
+    #[default]
+    And,
+    /// いずれかのタグが付いているもの
+    Or,
+}
+
+/// 並び順
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// 日付の古い順
+    Asc,
+    /// 日付の新しい順
+    #[default]
+    Desc,
+}
+
+/// ページングのカーソル
+/// 同じ日付のメディアを取りこぼさないように、最後の要素の日付と `media_id` を持つ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub date: NaiveDateTime,
+    pub media_id: MediaId,
+}
+
+impl Cursor {
+    /// URL に使える不透明な文字列にする
+    pub fn encode(&self) -> String {
+        let raw = format!(
+            "{}.{:09}:{}",
+            self.date.timestamp(),
+            self.date.timestamp_subsec_nanos(),
+            *self.media_id
+        );
+        base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// `encode` した文字列から戻す
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let raw = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)?;
+        let raw = String::from_utf8(raw)?;
+
+        let (date, media_id) = match raw.split_once(':') {
+            Some(parts) => parts,
+            None => bail!("invalid cursor: {}", cursor),
+        };
+        let (secs, nsecs) = match date.split_once('.') {
+            Some((secs, nsecs)) => (secs.parse::<i64>()?, nsecs.parse::<u32>()?),
+            None => bail!("invalid cursor: {}", cursor),
+        };
+        let date = match NaiveDateTime::from_timestamp_opt(secs, nsecs) {
+            Some(date) => date,
+            None => bail!("invalid cursor: {}", cursor),
+        };
+        ensure!(!media_id.is_empty(), "invalid cursor: {}", cursor);
+
+        Ok(Cursor {
+            date,
+            media_id: media_id.to_string().into(),
+        })
+    }
+}
+
+/// デフォルトの取得件数
+pub const DEFAULT_COUNT: u64 = 100;
+
+/// 一度に取得できる最大の件数
+pub const MAX_COUNT: u64 = 1000;
+
+/// `color` で絞り込む際のデフォルトの許容値
+pub const DEFAULT_COLOR_TOLERANCE: u32 = 48;
+
+/// `color` の許容値の最大、RGB 空間の対角線の長さ
+pub const MAX_COLOR_TOLERANCE: u32 = 442;
+
+/// MediaId を検索するためのフィルタ
+#[derive(Deserialize, Debug, Default)]
+pub struct IdsFilter {
+    /// この日時以降のものに絞り込む
+    /// ISO-8601 (`2022-01-20`, `2022-01-20T13:40:57`, `2022-01-20T13:40:57+09:00`) かミリ秒で指定する
+    /// タイムゾーンがない場合はサーバのローカル時刻として扱う
+    pub from: Option<String>,
+
+    /// この日時より前のものに絞り込む、形式は `from` と同じ
+    pub to: Option<String>,
+
+    /// `2022`, `2022-01`, `2022-01-20` の形式で年、月、日を指定して絞り込む
+    /// `from`, `to` とは同時に指定できない
+    pub date: Option<String>,
+
+    /// `date` やタイムゾーンのない `from`, `to` を解釈するタイムゾーン
+    /// `+09:00` や `Z` の形式で指定する、指定がない場合はサーバのローカル時刻として扱う
+    pub tz: Option<String>,
+
+    /// 取得件数を指定する
+    pub count: Option<u64>,
+
+    /// タグをカンマ区切りで指定する
+    pub tag: Option<String>,
+
+    /// 複数のタグを指定した場合の条件、デフォルトは `and`
+    pub tag_mode: Option<TagMode>,
+
+    /// このレーティング以上のものに絞り込む
+    pub rating_min: Option<u8>,
+
+    /// お気に入りかどうかで絞り込む
+    pub favorite: Option<bool>,
+
+    /// カラーラベルで絞り込む
+    pub label: Option<ColorLabel>,
+
+    /// `#RRGGBB` の形式で指定した色に近い代表色のものに絞り込む
+    pub color: Option<String>,
+
+    /// `color` との RGB 空間での距離の許容値、デフォルトは `DEFAULT_COLOR_TOLERANCE`
+    pub tolerance: Option<u32>,
+
+    /// 全文検索する文字列、空白で区切った語を全て含むものに絞り込む
+    pub q: Option<String>,
+
+    /// 前回のレスポンスで返した `cursor` を指定すると続きから取得する
+    pub cursor: Option<String>,
+
+    /// 並び順、デフォルトは `desc`
+    pub order: Option<SortOrder>,
+}
+
+impl IdsFilter {
+    /// パラメータを検証する
+    pub fn validate(&self) -> Result<()> {
+        let _ = self.count()?;
+        let _ = self.zone()?;
+        let _ = self.date_range()?;
+        let _ = self.parse_cursor()?;
+        let _ = self.color()?;
+        Ok(())
+    }
+
+    /// 取得件数、`None` の場合は `DEFAULT_COUNT` を使う
+    pub fn count(&self) -> Result<u64> {
+        let count = self.count.unwrap_or(DEFAULT_COUNT);
+        ensure!(
+            (1..=MAX_COUNT).contains(&count),
+            "count must be 1 to {}: {}",
+            MAX_COUNT,
+            count
+        );
+        Ok(count)
+    }
+
+    /// `tz` をパースする
+    pub fn zone(&self) -> Result<Zone> {
+        self.tz
+            .as_deref()
+            .map(Zone::parse)
+            .unwrap_or(Ok(Zone::Local))
+    }
+
+    /// 日付の範囲 (`from` 以上 `to` 未満) を UTC で返す
+    /// 指定されていない方は `None` で、範囲の端を開いたままにする
+    pub fn date_range(&self) -> Result<(Option<NaiveDateTime>, Option<NaiveDateTime>)> {
+        let zone = self.zone()?;
+        let (from, to) = match &self.date {
+            Some(date) => {
+                ensure!(
+                    self.from.is_none() && self.to.is_none(),
+                    "`date` cannot be used with `from` or `to`"
+                );
+                let (from, to) = parse_calendar_range(date, zone)?;
+                (Some(from), Some(to))
+            }
+            None => (
+                self.from
+                    .as_deref()
+                    .map(|from| parse_datetime(from, zone))
+                    .transpose()?,
+                self.to
+                    .as_deref()
+                    .map(|to| parse_datetime(to, zone))
+                    .transpose()?,
+            ),
+        };
+        if let (Some(from), Some(to)) = (from, to) {
+            ensure!(from < to, "`from` must be before `to`");
+        }
+        Ok((from, to))
+    }
+
+    /// `color` をパースして許容値と一緒に返す
+    pub fn color(&self) -> Result<Option<(Color, u32)>> {
+        let tolerance = self.tolerance.unwrap_or(DEFAULT_COLOR_TOLERANCE);
+        ensure!(
+            tolerance <= MAX_COLOR_TOLERANCE,
+            "tolerance must be 0 to {}: {}",
+            MAX_COLOR_TOLERANCE,
+            tolerance
+        );
+        self.color
+            .as_deref()
+            .map(|color| Ok((color.parse()?, tolerance)))
+            .transpose()
+    }
+
+    /// カーソルをパースする
+    pub fn parse_cursor(&self) -> Result<Option<Cursor>> {
+        self.cursor.as_deref().map(Cursor::decode).transpose()
+    }
+
+    /// 指定されたタグのリスト
+    pub fn tags(&self) -> Vec<String> {
+        self.tag
+            .iter()
+            .flat_map(|tags| tags.split(','))
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+
+    /// `metas` に対する where 句を組み立てる
+    pub(super) fn conditions(&self, include_private: bool) -> Result<Conditions> {
+        let (from, to) = self.date_range()?;
+
+        let mut conditions = Conditions::default();
+        conditions.push("deleted_at is null");
+        if let Some(from) = from {
+            conditions.push("date >= ?").bind(from);
+        }
+        if let Some(to) = to {
+            conditions.push("date < ?").bind(to);
+        }
+
+        if !include_private {
+            conditions
+                .push("visibility = ?")
+                .bind(MediaVisibility::Public);
+        }
+
+        if let Some(rating_min) = self.rating_min {
+            conditions.push("rating >= ?").bind(rating_min);
+        }
+        if let Some(favorite) = self.favorite {
+            conditions.push("favorite = ?").bind(favorite);
+        }
+        if let Some(label) = self.label {
+            conditions.push("label = ?").bind(label);
+        }
+        if let Some((color, tolerance)) = self.color()? {
+            // 代表色との距離の二乗で比べる
+            let (r, g, b) = color.rgb();
+            conditions
+                .push(
+                    r#"(
+                        (((dominant_color >> 16) & 255) - ?) * (((dominant_color >> 16) & 255) - ?)
+                        + (((dominant_color >> 8) & 255) - ?) * (((dominant_color >> 8) & 255) - ?)
+                        + ((dominant_color & 255) - ?) * ((dominant_color & 255) - ?)
+                    ) <= ?"#,
+                )
+                .bind(r)
+                .bind(r)
+                .bind(g)
+                .bind(g)
+                .bind(b)
+                .bind(b)
+                .bind(tolerance * tolerance);
+        }
+
+        let tags = self.tags();
+        if !tags.is_empty() {
+            let placeholders = vec!["?"; tags.len()].join(", ");
+            let clause = match self.tag_mode.unwrap_or_default() {
+                TagMode::Or => format!(
+                    r#"media_id in (
+                        select media_tags.media_id from media_tags
+                        join tags using (tag_id)
+                        where tags.name in ({})
+                    )"#,
+                    placeholders
+                ),
+                TagMode::And => format!(
+                    r#"media_id in (
+                        select media_tags.media_id from media_tags
+                        join tags using (tag_id)
+                        where tags.name in ({})
+                        group by media_tags.media_id
+                        having count(distinct tags.tag_id) = {}
+                    )"#,
+                    placeholders,
+                    tags.len()
+                ),
+            };
+            conditions.push(clause);
+            for tag in tags {
+                conditions.bind(tag);
+            }
+        }
+
+        for term in self.q.iter().flat_map(|q| split_terms(q)) {
+            if term.chars().count() >= MIN_MATCH_LENGTH {
+                conditions
+                    .push("media_id in (select media_id from metas_fts where metas_fts match ?)")
+                    .bind(quote_term(&term));
+            } else {
+                // trigram で検索できない短い語は like で探す
+                let pattern = format!(
+                    "%{}%",
+                    term.replace('\\', "\\\\")
+                        .replace('%', "\\%")
+                        .replace('_', "\\_")
+                );
+                conditions.push(
+                    r#"media_id in (
+                        select media_id from metas_fts
+                        where origin like ? escape '\' or title like ? escape '\'
+                            or description like ? escape '\' or tags like ? escape '\'
+                            or attributes like ? escape '\'
+                    )"#,
+                );
+                for _ in 0..5 {
+                    conditions.bind(pattern.clone());
+                }
+            }
+        }
+
+        Ok(conditions)
+    }
+}
+
+/// 日時を解釈するタイムゾーン
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// サーバのローカル時刻
+    Local,
+    /// UTC からの固定のオフセット
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    /// `Z`, `UTC`, `+09:00`, `+0900`, `-05:00` の形式をパースする
+    pub fn parse(value: &str) -> Result<Self> {
+        if value == "Z" || value.eq_ignore_ascii_case("utc") {
+            return Ok(Zone::Fixed(FixedOffset::east(0)));
+        }
+
+        let (sign, offset) = match value.split_at(value.len().min(1)) {
+            ("+", offset) => (1, offset),
+            ("-", offset) => (-1, offset),
+            _ => bail!("invalid time zone: {}", value),
+        };
+        let offset = offset.replace(':', "");
+        ensure!(
+            offset.len() == 4 && offset.chars().all(|c| c.is_ascii_digit()),
+            "invalid time zone: {}",
+            value
+        );
+        let hours: i32 = offset[..2].parse()?;
+        let minutes: i32 = offset[2..].parse()?;
+        ensure!(hours < 24 && minutes < 60, "invalid time zone: {}", value);
+
+        Ok(Zone::Fixed(FixedOffset::east(
+            sign * (hours * 60 * 60 + minutes * 60),
+        )))
+    }
+
+    /// このタイムゾーンの日時を UTC にする
+    pub fn to_utc(self, date: NaiveDateTime) -> Result<NaiveDateTime> {
+        let date = match self {
+            Zone::Local => Local
+                .from_local_datetime(&date)
+                .map(|date| date.naive_utc()),
+            Zone::Fixed(offset) => offset
+                .from_local_datetime(&date)
+                .map(|date| date.naive_utc()),
+        };
+        match date {
+            LocalResult::Single(date) | LocalResult::Ambiguous(date, _) => Ok(date),
+            LocalResult::None => bail!("invalid local datetime"),
+        }
+    }
+
+    /// UTC の日時をこのタイムゾーンにする SQLite の日時関数の修飾子
+    /// `Local` の場合は夏時間が変わっても合うように、行ごとにオフセットを求める `localtime` を使う
+    pub fn sqlite_modifier(self) -> String {
+        match self {
+            Zone::Local => "localtime".to_string(),
+            Zone::Fixed(offset) => format!("{} seconds", offset.local_minus_utc()),
+        }
+    }
+}
+
+/// ISO-8601 の日時かミリ秒をパースして UTC にする
+/// タイムゾーンがない場合は `zone` の日時として扱う
+pub fn parse_datetime(value: &str, zone: Zone) -> Result<NaiveDateTime> {
+    if let Ok(milli) = value.parse::<i64>() {
+        return match NaiveDateTime::from_timestamp_opt(
+            milli.div_euclid(1000),
+            (milli.rem_euclid(1000) * 1_000_000) as u32,
+        ) {
+            Some(date) => Ok(date),
+            None => bail!("invalid timestamp: {}", value),
+        };
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Ok(date.naive_utc());
+    }
+    for format in [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M:%S%.f",
+    ] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return zone.to_utc(date);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return zone.to_utc(date.and_hms(0, 0, 0));
+    }
+    bail!("invalid datetime: {}", value)
+}
+
+/// `2022`, `2022-01`, `2022-01-20` をその年、月、日の範囲にして UTC で返す
+pub fn parse_calendar_range(value: &str, zone: Zone) -> Result<(NaiveDateTime, NaiveDateTime)> {
+    let parts = value
+        .split('-')
+        .map(|part| part.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("invalid date: {}", value))?;
+
+    // 範囲外の年や、最後の日の翌日はパニックせずに不正な日付として扱う
+    let date = |year: u32, month: u32, day: u32| {
+        i32::try_from(year)
+            .ok()
+            .and_then(|year| NaiveDate::from_ymd_opt(year, month, day))
+    };
+    let next_year = |year: u32| {
+        i32::try_from(year)
+            .ok()
+            .and_then(|year| year.checked_add(1))
+            .and_then(|year| NaiveDate::from_ymd_opt(year, 1, 1))
+    };
+    let (from, to) = match *parts.as_slice() {
+        [year] => (date(year, 1, 1), next_year(year)),
+        [year, month] => {
+            let from = date(year, month, 1);
+            let to = match month {
+                12 => next_year(year),
+                _ => date(year, month + 1, 1),
+            };
+            (from, to)
+        }
+        [year, month, day] => {
+            let from = date(year, month, day);
+            (from, from.and_then(|from| from.succ_opt()))
+        }
+        _ => (None, None),
+    };
+
+    match (from, to) {
+        (Some(from), Some(to)) => Ok((
+            zone.to_utc(from.and_hms(0, 0, 0))?,
+            zone.to_utc(to.and_hms(0, 0, 0))?,
+        )),
+        _ => bail!("invalid date: {}", value),
+    }
+}
+
+/// 動的に組み立てる where 句
+/// 句は `and` で連結し、プレースホルダ `?` の値は追加した順にバインドする
+#[derive(Default)]
+pub(super) struct Conditions {
+    clauses: Vec<String>,
+    arguments: SqliteArguments<'static>,
+}
+
+impl Conditions {
+    /// 句を追加する
+    pub fn push(&mut self, clause: impl Into<String>) -> &mut Self {
+        self.clauses.push(clause.into());
+        self
+    }
+
+    /// 直前に追加した句のプレースホルダの値をバインドする
+    pub fn bind<T>(&mut self, value: T) -> &mut Self
+    where
+        T: 'static + Send + Encode<'static, Sqlite> + Type<Sqlite>,
+    {
+        self.arguments.add(value);
+        self
+    }
+
+    /// where 句の SQL とバインドする値に分解する
+    pub fn into_parts(self) -> (String, SqliteArguments<'static>) {
+        let sql = if self.clauses.is_empty() {
+            "1".to_string()
+        } else {
+            self.clauses.join(" and ")
+        };
+        (sql, self.arguments)
+    }
+}
+
+#[derive(FromRow, Debug, Clone)]
+struct MediaIdWithDateRow {
+    pub media_id: MediaId,
+    pub date: NaiveDateTime,
+}
+
+/// カーソルを作ることができる行
+trait CursorRow {
+    fn cursor(&self) -> Cursor;
+}
+
+impl CursorRow for MediaIdWithDateRow {
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            date: self.date,
+            media_id: self.media_id.clone(),
+        }
+    }
+}
+
+impl CursorRow for MediaMetaWithTags {
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            date: self.meta.date,
+            media_id: self.meta.media_id.clone(),
+        }
+    }
+}
+
+/// `MediaId::filter` の結果
+#[derive(Debug, Clone)]
+pub struct FilteredIds {
+    pub ids: Vec<MediaId>,
+    /// 続きを取得するためのカーソル、結果が空の場合は `None`
+    pub cursor: Option<Cursor>,
+    /// 続きがあるかどうか
+    pub has_more: bool,
+}
+
+/// `MediaMetaWithTags::filter` の結果
+#[derive(Debug, Clone)]
+pub struct FilteredMetas {
+    pub metas: Vec<MediaMetaWithTags>,
+    /// 続きを取得するためのカーソル、結果が空の場合は `None`
+    pub cursor: Option<Cursor>,
+    /// 続きがあるかどうか
+    pub has_more: bool,
+}
+
+/// `columns` の列を日付と `media_id` の順に検索して取得する
+/// 成功すると行のリストと続きを取得するためのカーソル、続きがあるかどうかを返す
+async fn filter_rows<R>(
+    conn: &mut SqliteConnection,
+    option: IdsFilter,
+    include_private: bool,
+    columns: &str,
+) -> Result<(Vec<R>, Option<Cursor>, bool)>
+where
+    R: for<'r> FromRow<'r, SqliteRow> + CursorRow + Send + Unpin,
+{
+    let count = option.count()?;
+    let order = option.order.unwrap_or_default();
+    let cursor = option.parse_cursor()?;
+
+    let mut conditions = option.conditions(include_private)?;
+    if let Some(cursor) = cursor {
+        // 日付が同じ場合は media_id で順序を決める
+        let clause = match order {
+            SortOrder::Asc => "(date > ? or (date = ? and media_id > ?))",
+            SortOrder::Desc => "(date < ? or (date = ? and media_id < ?))",
+        };
+        conditions
+            .push(clause)
+            .bind(cursor.date)
+            .bind(cursor.date)
+            .bind(cursor.media_id.to_string());
+    }
+    let (conditions, mut arguments) = conditions.into_parts();
+    // 続きがあるかを調べるために 1 件多く取得する
+    arguments.add(count as i64 + 1);
+
+    let order = match order {
+        SortOrder::Asc => "asc",
+        SortOrder::Desc => "desc",
+    };
+    let sql = format!(
+        r#"
+        select {} from metas
+        where {}
+        order by date {order}, media_id {order}
+        limit ?
+        "#,
+        columns,
+        conditions,
+        order = order
+    );
+    let mut rows: Vec<R> = query_as_with(&sql, arguments).fetch_all(conn).await?;
+
+    let has_more = rows.len() as u64 > count;
+    rows.truncate(count as usize);
+    let cursor = rows.last().map(CursorRow::cursor);
+
+    Ok((rows, cursor, has_more))
+}
+
+impl MediaId {
+    /// `MediaId` を日付と `media_id` の順に検索して取得する関数
+    /// 成功すると `MediaId` のリストと続きを取得するためのカーソルを返す
+    pub async fn filter(
+        conn: &mut SqliteConnection,
+        option: IdsFilter,
+        include_private: bool,
+    ) -> Result<FilteredIds> {
+        let (rows, cursor, has_more) =
+            filter_rows::<MediaIdWithDateRow>(conn, option, include_private, "media_id, date")
+                .await?;
+
+        Ok(FilteredIds {
+            ids: rows.into_iter().map(|row| row.media_id).collect(),
+            cursor,
+            has_more,
+        })
+    }
+}
+
+impl MediaMetaWithTags {
+    /// `MediaId::filter` と同じ条件でメタ情報をまとめて取得する
+    pub async fn filter(
+        conn: &mut SqliteConnection,
+        option: IdsFilter,
+        include_private: bool,
+    ) -> Result<FilteredMetas> {
+        let columns = format!("metas.*, {}", TAGS_COLUMN);
+        let (metas, cursor, has_more) =
+            filter_rows(conn, option, include_private, &columns).await?;
+
+        Ok(FilteredMetas {
+            metas,
+            cursor,
+            has_more,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MIGRATOR;
+    use crate::media::MediaMeta;
+    use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions};
+    use std::{collections::HashSet, str::FromStr};
+
+    /// 同じ日付のものが続くメディアを入れたデータベースを作る
+    /// 戻り値は日付と `media_id` の昇順に並べた `media_id`
+    async fn setup() -> (SqliteConnection, Vec<String>) {
+        let mut conn = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        MIGRATOR.run(&mut conn).await.unwrap();
+
+        let burst = NaiveDate::from_ymd(2022, 1, 20).and_hms(13, 40, 57);
+        let dates = (0..2)
+            .map(|i| burst - chrono::Duration::hours(i + 1))
+            .chain((0..7).map(|_| burst))
+            .chain((0..3).map(|i| burst + chrono::Duration::hours(i + 1)));
+
+        let mut metas = vec![];
+        for (i, date) in dates.enumerate() {
+            let meta = MediaMeta::new(format!("/origin/{}.jpg", i), vec![i as u8], date);
+            meta.save(&mut conn).await.unwrap();
+            metas.push((date, meta.media_id.to_string()));
+        }
+        metas.sort();
+        (conn, metas.into_iter().map(|(_, media_id)| media_id).collect())
+    }
+
+    /// カーソルを辿って全てのページを取得する
+    async fn paginate(conn: &mut SqliteConnection, order: SortOrder, count: u64) -> Vec<String> {
+        let mut ids = vec![];
+        let mut cursor = None;
+        // カーソルが進まない場合に止まるように、件数より多くは取得しない
+        for _ in 0..=12 {
+            let option = IdsFilter {
+                count: Some(count),
+                order: Some(order),
+                cursor: cursor.as_ref().map(Cursor::encode),
+                ..Default::default()
+            };
+            let page = MediaId::filter(conn, option, true).await.unwrap();
+            assert!(page.ids.len() as u64 <= count);
+            ids.extend(page.ids.iter().map(|id| id.to_string()));
+            if !page.has_more {
+                return ids;
+            }
+            // 続きがある場合は満杯のページになっている
+            assert_eq!(page.ids.len() as u64, count);
+            cursor = page.cursor;
+        }
+        panic!("cursor does not advance: {:?}", ids);
+    }
+
+    async fn assert_pages(order: SortOrder) {
+        let (mut conn, mut expected) = setup().await;
+        if order == SortOrder::Desc {
+            expected.reverse();
+        }
+        // 12 件を、同じ日付の 7 件がページをまたぐ大きさと、ちょうど割り切れる大きさで取得する
+        for count in [1, 2, 3, 4, 5, 12, 100] {
+            let ids = paginate(&mut conn, order, count).await;
+            assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+            assert_eq!(ids, expected, "count={}", count);
+        }
+    }
+
+    #[tokio::test]
+    async fn paginate_same_date_asc() {
+        assert_pages(SortOrder::Asc).await;
+    }
+
+    #[tokio::test]
+    async fn paginate_same_date_desc() {
+        assert_pages(SortOrder::Desc).await;
+    }
+
+    #[tokio::test]
+    async fn has_more_on_exact_page() {
+        let (mut conn, _) = setup().await;
+        let option = IdsFilter {
+            count: Some(12),
+            ..Default::default()
+        };
+        let page = MediaId::filter(&mut conn, option, true).await.unwrap();
+        assert_eq!(page.ids.len(), 12);
+        assert!(!page.has_more);
+
+        let option = IdsFilter {
+            count: Some(11),
+            ..Default::default()
+        };
+        let page = MediaId::filter(&mut conn, option, true).await.unwrap();
+        assert_eq!(page.ids.len(), 11);
+        assert!(page.has_more);
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor {
+            date: NaiveDate::from_ymd(2022, 1, 20).and_hms_nano(13, 40, 57, 123_456_789),
+            media_id: "a-b-c".to_string().into(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(Cursor::decode("not a cursor").is_err());
+    }
+
+    fn utc() -> Zone {
+        Zone::Fixed(FixedOffset::east(0))
+    }
+
+    #[test]
+    fn calendar_range_of_year_month_and_day() {
+        let jst = Zone::Fixed(FixedOffset::east(9 * 60 * 60));
+        assert_eq!(
+            parse_calendar_range("2022", utc()).unwrap(),
+            (
+                NaiveDate::from_ymd(2022, 1, 1).and_hms(0, 0, 0),
+                NaiveDate::from_ymd(2023, 1, 1).and_hms(0, 0, 0)
+            )
+        );
+        assert_eq!(
+            parse_calendar_range("2022-12", utc()).unwrap(),
+            (
+                NaiveDate::from_ymd(2022, 12, 1).and_hms(0, 0, 0),
+                NaiveDate::from_ymd(2023, 1, 1).and_hms(0, 0, 0)
+            )
+        );
+        assert_eq!(
+            parse_calendar_range("2022-01-20", jst).unwrap(),
+            (
+                NaiveDate::from_ymd(2022, 1, 19).and_hms(15, 0, 0),
+                NaiveDate::from_ymd(2022, 1, 20).and_hms(15, 0, 0)
+            )
+        );
+    }
+
+    #[test]
+    fn calendar_range_rejects_invalid_dates() {
+        for value in [
+            "",
+            "2022-13",
+            "2022-02-30",
+            "2022-01-20-1",
+            "-2022",
+            "2022-1x",
+            // 年が i32 に収まらない
+            "4294967295",
+            // 翌年や翌日が表せない
+            "262143",
+            "262143-12",
+            "262143-12-31",
+        ] {
+            let err = parse_calendar_range(value, utc()).unwrap_err();
+            assert_eq!(err.to_string(), format!("invalid date: {}", value));
+        }
+    }
+
+    #[test]
+    fn datetime_formats() {
+        let jst = Zone::Fixed(FixedOffset::east(9 * 60 * 60));
+        let expected = NaiveDate::from_ymd(2022, 1, 20).and_hms(4, 40, 57);
+        assert_eq!(
+            parse_datetime("2022-01-20T13:40:57+09:00", utc()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_datetime("2022-01-20T13:40:57", jst).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_datetime("2022-01-20 13:40:57", jst).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_datetime("2022-01-20T13:40", jst).unwrap(),
+            expected - chrono::Duration::seconds(57)
+        );
+        assert_eq!(
+            parse_datetime("2022-01-20", jst).unwrap(),
+            NaiveDate::from_ymd(2022, 1, 19).and_hms(15, 0, 0)
+        );
+        assert_eq!(
+            parse_datetime("1642653657123", jst).unwrap(),
+            NaiveDate::from_ymd(2022, 1, 20).and_hms_milli(4, 40, 57, 123)
+        );
+        assert!(parse_datetime("2022-01-20T25:00", utc()).is_err());
+    }
+
+    #[test]
+    fn datetime_of_bare_year_is_milliseconds() {
+        // 数字だけの場合はミリ秒として扱うので、年だけを渡すと 1970 年になる
+        assert_eq!(
+            parse_datetime("2022", utc()).unwrap(),
+            NaiveDate::from_ymd(1970, 1, 1).and_hms_milli(0, 0, 2, 22)
+        );
+        assert_eq!(
+            parse_datetime("-1000", utc()).unwrap(),
+            NaiveDate::from_ymd(1969, 12, 31).and_hms(23, 59, 59)
+        );
+        assert!(parse_datetime(&i64::MAX.to_string(), utc()).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+use super::{media::*, quarantine::*};
+use anyhow::Result;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::*, query_as, SqliteConnection, SqlitePool};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::sync::{watch, Notify};
+
+/// この回数失敗したら諦める
+pub const MAX_ATTEMPTS: u32 = 5;
+
+// 再試行までの待ち時間の初期値、失敗するたびに倍にする
+const BACKOFF_BASE_SECONDS: i64 = 5;
+
+// 実行中のまま、この時間更新されていないジョブは止まったものとして取り出し直す
+const RUNNING_TIMEOUT_SECONDS: i64 = 30 * 60;
+
+// 処理するジョブがない時に確かめ直す間隔
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// 終わっていないジョブを一度に数えるファイルの数
+const COUNT_CHUNK_SIZE: usize = 500;
+
+/// 取り込みジョブの状態
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[repr(u32)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "done" => Ok(JobStatus::Done),
+            "failed" => Ok(JobStatus::Failed),
+            _ => bail!("unknown status: {}", s),
+        }
+    }
+}
+
+/// ファイルを取り込むジョブ
+/// ファイルごとに一つで、同じファイルを再び追加した場合はやり直す
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct Job {
+    pub job_id: i64,
+    pub origin: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// 取り込めた場合のメディア
+    pub media_id: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// この日時以降に実行する
+    pub next_run_at: NaiveDateTime,
+    /// 画像に添えられた JSON からメタ情報を読み込む、どのプロセスのワーカーが実行しても同じにする
+    pub sidecar: bool,
+}
+
+impl Job {
+    /// ジョブを追加する、実行中でなければ最初からやり直す
+    pub async fn enqueue(conn: &mut SqliteConnection, origin: &str, sidecar: bool) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        let _ = sqlx::query(
+            r#"
+            insert into jobs (origin, status, attempts, created_at, updated_at, next_run_at, sidecar)
+            values (?1, ?2, 0, ?3, ?3, ?3, ?5)
+            on conflict (origin) do update
+            set status = ?2, attempts = 0, last_error = null, updated_at = ?3, next_run_at = ?3,
+                sidecar = ?5
+            where status != ?4
+            "#,
+        )
+        .bind(origin)
+        .bind(JobStatus::Queued)
+        .bind(now)
+        .bind(JobStatus::Running)
+        .bind(sidecar)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_by_origin(conn: &mut SqliteConnection, origin: &str) -> Result<Option<Self>> {
+        let job = query_as("select * from jobs where origin = ?")
+            .bind(origin)
+            .fetch_optional(conn)
+            .await?;
+        Ok(job)
+    }
+
+    /// 実行できるジョブを一つ取り出して実行中にする
+    /// 実行中のまま `RUNNING_TIMEOUT_SECONDS` 以上経ったジョブも、止まったものとして取り出す
+    /// `returning` ではコミットされないことがあるので、状態を確かめながら更新してから取得し直す
+    async fn claim(conn: &mut SqliteConnection) -> Result<Option<Self>> {
+        let now = Utc::now().naive_utc();
+        let expired = now - Duration::seconds(RUNNING_TIMEOUT_SECONDS);
+        loop {
+            let candidates: Vec<(i64, JobStatus, NaiveDateTime)> = query_as(
+                r#"
+                select job_id, status, updated_at from jobs
+                where (status = ? and next_run_at <= ?) or (status = ? and updated_at < ?)
+                order by next_run_at, job_id
+                limit 1
+                "#,
+            )
+            .bind(JobStatus::Queued)
+            .bind(now)
+            .bind(JobStatus::Running)
+            .bind(expired)
+            .fetch_all(&mut *conn)
+            .await?;
+            let (job_id, status, updated_at) = match candidates.first() {
+                Some(candidate) => *candidate,
+                None => return Ok(None),
+            };
+
+            let mut tx = Connection::begin(&mut *conn).await?;
+            let result = sqlx::query(
+                r#"
+                update jobs
+                set status = ?, attempts = attempts + 1, updated_at = ?
+                where job_id = ? and status = ? and updated_at = ?
+                "#,
+            )
+            .bind(JobStatus::Running)
+            .bind(now)
+            .bind(job_id)
+            .bind(status)
+            .bind(updated_at)
+            .execute(&mut tx)
+            .await?;
+            // 他のワーカーが先に取り出した場合は次を探す
+            if result.rows_affected() == 0 {
+                tx.commit().await?;
+                continue;
+            }
+            let job = query_as("select * from jobs where job_id = ?")
+                .bind(job_id)
+                .fetch_one(&mut tx)
+                .await?;
+            tx.commit().await?;
+            return Ok(Some(job));
+        }
+    }
+
+    async fn complete(&self, conn: &mut SqliteConnection, media_id: &str) -> Result<()> {
+        let _ = sqlx::query(
+            r#"
+            update jobs
+            set status = ?, last_error = null, media_id = ?, updated_at = ?
+            where job_id = ?
+            "#,
+        )
+        .bind(JobStatus::Done)
+        .bind(media_id)
+        .bind(Utc::now().naive_utc())
+        .bind(self.job_id)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// 失敗を記録する
+    /// `retry` が `true` で試行回数が残っていれば、待ち時間を倍にしながら再試行する
+    async fn fail(&self, conn: &mut SqliteConnection, error: &str, retry: bool) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        let (status, next_run_at) = if retry && self.attempts < MAX_ATTEMPTS {
+            let backoff = BACKOFF_BASE_SECONDS << self.attempts.saturating_sub(1);
+            (JobStatus::Queued, now + Duration::seconds(backoff))
+        } else {
+            (JobStatus::Failed, now)
+        };
+        let _ = sqlx::query(
+            r#"
+            update jobs
+            set status = ?, last_error = ?, updated_at = ?, next_run_at = ?
+            where job_id = ?
+            "#,
+        )
+        .bind(status)
+        .bind(error)
+        .bind(now)
+        .bind(next_run_at)
+        .bind(self.job_id)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// ジョブの一覧を新しく更新された順に取得する
+    pub async fn list(
+        conn: &mut SqliteConnection,
+        status: Option<JobStatus>,
+        count: u64,
+    ) -> Result<Vec<Self>> {
+        let jobs = query_as(
+            r#"
+            select * from jobs
+            where ?1 is null or status = ?1
+            order by updated_at desc, job_id desc
+            limit ?2
+            "#,
+        )
+        .bind(status)
+        .bind(count as i64)
+        .fetch_all(conn)
+        .await?;
+        Ok(jobs)
+    }
+
+    /// 失敗したジョブをやり直す、`job_ids` が空の場合は失敗したもの全て
+    pub async fn retry(conn: &mut SqliteConnection, job_ids: &[i64]) -> Result<u64> {
+        let mut tx = Connection::begin(conn).await?;
+        let mut retried = 0;
+        let failed = Job::list(&mut tx, Some(JobStatus::Failed), i64::MAX as u64).await?;
+        for job in failed {
+            if job_ids.is_empty() || job_ids.contains(&job.job_id) {
+                Job::enqueue(&mut tx, &job.origin, job.sidecar).await?;
+                retried += 1;
+            }
+        }
+        tx.commit().await?;
+        Ok(retried)
+    }
+
+    /// 指定した状態のジョブを削除する
+    pub async fn clear(conn: &mut SqliteConnection, status: JobStatus) -> Result<u64> {
+        let result = sqlx::query("delete from jobs where status = ?")
+            .bind(status)
+            .execute(conn)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// 待っているか実行中のジョブの数
+    async fn count_unfinished(conn: &mut SqliteConnection, origins: &[PathBuf]) -> Result<i64> {
+        let mut count = 0;
+        // 変数の数の上限を超えないように分けて数える
+        for origins in origins.chunks(COUNT_CHUNK_SIZE) {
+            let sql = format!(
+                "select count(*) from jobs where status in (?, ?) and origin in ({})",
+                vec!["?"; origins.len()].join(", ")
+            );
+            let mut query = query_as(&sql)
+                .bind(JobStatus::Queued)
+                .bind(JobStatus::Running);
+            for origin in origins {
+                query = query.bind(origin.to_string_lossy().to_string());
+            }
+            let (unfinished,): (i64,) = query.fetch_one(&mut *conn).await?;
+            count += unfinished;
+        }
+        Ok(count)
+    }
+}
+
+/// ジョブを取り出してメディアを生成するワーカー
+#[derive(Clone)]
+pub struct JobWorkers {
+    pool: SqlitePool,
+    data_dir: PathBuf,
+    option: Arc<MediaGenerateOption>,
+    /// ジョブを追加したことをワーカーに伝える
+    notify: Arc<Notify>,
+}
+
+impl JobWorkers {
+    pub fn new(pool: SqlitePool, data_dir: &Path, option: MediaGenerateOption) -> Self {
+        JobWorkers {
+            pool,
+            data_dir: data_dir.to_owned(),
+            option: Arc::new(option),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// ファイルをジョブとして追加する、JSON を読むかどうかもジョブに記録する
+    pub async fn enqueue(&self, origins: &[PathBuf]) -> Result<()> {
+        if origins.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = Connection::begin(&mut *conn).await?;
+        for origin in origins {
+            Job::enqueue(&mut tx, &origin.to_string_lossy(), self.option.sidecar).await?;
+        }
+        tx.commit().await?;
+        self.notify.notify_waiters();
+        Ok(())
+    }
+
+    /// `workers` 個のワーカーでジョブを処理する
+    /// `shutdown` が `true` になったら、実行中のジョブを終えてから止まる
+    pub async fn run(&self, workers: usize, shutdown: watch::Receiver<bool>) -> Result<()> {
+        self.spawn(workers, shutdown, None).await
+    }
+
+    /// `origins` のジョブが全て終わるまで処理する
+    /// 他のプロセスが実行中のものも終わるまで待ち、止まったものは期限が過ぎたら取り出し直す
+    /// 他から追加されたジョブも取り出すが、それらが終わるのは待たない
+    pub async fn drain(&self, origins: &[PathBuf], workers: usize) -> Result<()> {
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let origins = Arc::new(origins.to_vec());
+        self.spawn(workers, shutdown_rx, Some(origins)).await
+    }
+
+    async fn spawn(
+        &self,
+        workers: usize,
+        shutdown: watch::Receiver<bool>,
+        until_done: Option<Arc<Vec<PathBuf>>>,
+    ) -> Result<()> {
+        let handles = (0..workers.max(1))
+            .map(|_| {
+                let worker = self.clone();
+                let shutdown = shutdown.clone();
+                let until_done = until_done.clone();
+                tokio::spawn(async move { worker.work(shutdown, until_done).await })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await??;
+        }
+        Ok(())
+    }
+
+    /// データベースがロックされているなどの一時的なエラーではワーカーを止めず、ログに残して続ける
+    async fn work(
+        &self,
+        mut shutdown: watch::Receiver<bool>,
+        until_done: Option<Arc<Vec<PathBuf>>>,
+    ) -> Result<()> {
+        while !*shutdown.borrow() {
+            match self.claim().await {
+                Ok(Some(job)) => {
+                    if let Err(e) = self.process(&job).await {
+                        log::warn!("failed to process job {}: {:?}", job.job_id, e);
+                        self.retry_later(&job, &e).await;
+                    }
+                    continue;
+                }
+                Ok(None) => {
+                    if let Some(origins) = &until_done {
+                        match self.count_unfinished(origins).await {
+                            Ok(0) => break,
+                            Ok(_) => {}
+                            Err(e) => log::warn!("failed to count jobs: {:?}", e),
+                        }
+                    }
+                }
+                Err(e) => log::warn!("failed to claim job: {:?}", e),
+            }
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = shutdown.changed() => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn claim(&self) -> Result<Option<Job>> {
+        Job::claim(&mut *self.pool.acquire().await?).await
+    }
+
+    /// `origins` のうち、待っているか実行中のジョブの数
+    pub async fn count_unfinished(&self, origins: &[PathBuf]) -> Result<i64> {
+        Job::count_unfinished(&mut *self.pool.acquire().await?, origins).await
+    }
+
+    /// 処理中にエラーになったジョブを、待ち時間を置いてやり直すように記録する
+    /// 実行中のまま残さないように、記録できるまで繰り返す
+    async fn retry_later(&self, job: &Job, error: &anyhow::Error) {
+        loop {
+            let result = match self.pool.acquire().await {
+                Ok(mut conn) => job.fail(&mut conn, &error.to_string(), true).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => return,
+                Err(e) => {
+                    log::warn!("failed to record job {} failure: {:?}", job.job_id, e);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// 読み込めないファイルは隔離して、変更されるまで取り込み直さない
+    async fn process(&self, job: &Job) -> Result<()> {
+        let origin = Path::new(&job.origin);
+        let mut conn = self.pool.acquire().await?;
+
+        let (size, modified) = match std::fs::metadata(origin)
+            .and_then(|metadata| Ok((metadata.len(), metadata.modified()?)))
+        {
+            Ok((size, modified)) => (size, DateTime::<Utc>::from(modified).naive_utc()),
+            Err(e) => {
+                // 消えたファイルは再試行しない
+                return job.fail(&mut conn, &e.to_string(), false).await;
+            }
+        };
+
+        if let Some(quarantine) = Quarantine::get(&mut conn, &job.origin).await? {
+            if quarantine.is_unchanged(size, modified) {
+                return job.fail(&mut conn, &quarantine.error, false).await;
+            }
+        }
+        drop(conn);
+
+        log::info!("start origin ({:#?})", origin);
+        // 追加したプロセスの設定に合わせる
+        let option = MediaGenerateOption {
+            sidecar: job.sidecar,
+            ..(*self.option).clone()
+        };
+        let result = Media::generate(&self.pool, origin, &self.data_dir, &option).await;
+
+        let mut conn = self.pool.acquire().await?;
+        match result {
+            Ok(media) => {
+                log::info!("{:#?}: OK", media.meta.origin);
+                Quarantine::remove(&mut conn, &job.origin).await?;
+                job.complete(&mut conn, &media.meta.media_id).await
+            }
+            Err(e) if e.downcast_ref::<image::ImageError>().is_some() => {
+                log::warn!("quarantine {:#?}: {:?}", origin, e);
+                let error = e.to_string();
+                Quarantine::put(&mut conn, &job.origin, size, modified, &error).await?;
+                job.fail(&mut conn, &error, false).await
+            }
+            Err(e) => {
+                log::info!("{:#?}: {:?}", origin, e);
+                job.fail(&mut conn, &e.to_string(), true).await
+            }
+        }
+    }
+}
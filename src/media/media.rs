@@ -1,22 +1,17 @@
-use super::{common::*, meta::*};
+use super::{
+    common::*, job::*, meta::*, scan::ScanOption, sidecar::Sidecar, tag::Tag, thumb::ThumbOption,
+};
 use anyhow::Result;
 use chrono::prelude::*;
-use sqlx::{Connection, SqliteConnection};
+use sqlx::{SqliteConnection, SqlitePool};
 use std::path::Path;
 use tokio::task;
 
 // サムネイルの画像ファイル名
 const THUMB_FILE_NAME: &str = "thumb.jpg";
 
-// SQLite3データベースを返す
-pub async fn create_connection(data_directory: &Path) -> Result<SqliteConnection> {
-    let conn = SqliteConnection::connect(&format!(
-        "sqlite://{}/db.sqlite3",
-        data_directory.to_string_lossy()
-    ))
-    .await?;
-    Ok(conn)
-}
+// 一括で生成する時に進み具合を確かめる間隔
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
 
 #[derive(Debug, Clone)]
 pub struct Media {
@@ -29,33 +24,73 @@ impl From<MediaMeta> for Media {
     }
 }
 
-#[derive(Default)]
-pub struct MediaGenerateOption {}
+#[derive(Default, Debug, Clone)]
+pub struct MediaGenerateOption {
+    pub thumb: ThumbOption,
+    /// ディレクトリから探す際のルール
+    pub scan: ScanOption,
+    /// エクスポートした画像に添えられた JSON からメタ情報を読み込む
+    pub sidecar: bool,
+}
 
 impl Media {
     /// ファイルを指定して生成する
     pub async fn generate(
+        pool: &SqlitePool,
+        origin: &Path,
+        data_directory: &Path,
+        option: &MediaGenerateOption,
+    ) -> Result<Self> {
+        // ファイルのハッシュ値を取得する
+        let hashed = get_file_hash(origin).await?;
+
+        Media::generate_hashed(pool, origin, hashed, data_directory, option).await
+    }
+
+    /// ハッシュ値を計算済みのファイルから生成する
+    pub async fn generate_hashed(
+        pool: &SqlitePool,
         origin: &Path,
+        hashed: Vec<u8>,
         data_directory: &Path,
-        _option: &MediaGenerateOption,
+        option: &MediaGenerateOption,
     ) -> Result<Self> {
         use super::thumb::create_thumb;
         use tokio::fs::*;
 
-        let mut conn = create_connection(data_directory).await?;
+        let sidecar = if option.sidecar {
+            Sidecar::find(origin).await.unwrap_or_else(|e| {
+                log::warn!("skip sidecar of {:?}: {:?}", origin, e);
+                None
+            })
+        } else {
+            None
+        };
+        let from_sidecar = sidecar.is_some();
+        let sidecar = sidecar.unwrap_or_default();
 
-        // ファイルのハッシュ値を取得する
-        let hashed = get_file_hash(origin).await?;
+        // サムネイルを作る間はコネクションを返しておき、他のリクエストを待たせない
+        let mut conn = pool.acquire().await?;
 
         // ハッシュ値が一致している場合は生成しない
-        if let Ok(meta) = MediaMeta::get_by_hashed(&mut conn, &hashed).await {
+        if let Ok(mut meta) = MediaMeta::get_by_hashed(&mut conn, &hashed).await {
             log::debug!("Already media created. file={:#?}", origin);
+            // 元のファイルが移動していた場合はパスを付け替える
+            if meta.missing_at.is_some() || !Path::new(&meta.origin).exists() {
+                meta.update_origin(&mut conn, &origin.to_string_lossy())
+                    .await?;
+            }
+            // 同じ画像が複数のアルバムに入っている場合はタグを足していく
+            attach_albums(&mut conn, &meta, &sidecar).await?;
             return Ok(meta.into());
         }
+        drop(conn);
 
         // 日付を取得する
-        // exif -> file created at -> now とフォールバックしたい
-        let date = if let Ok(date) = get_exif_date(origin).await {
+        // sidecar -> exif -> file created at -> now とフォールバックしたい
+        let date = if let Some(date) = sidecar.date {
+            date
+        } else if let Ok(date) = get_exif_date(origin).await {
             date
         } else if let Ok(date) = get_file_created_date(origin).await {
             date
@@ -63,54 +98,133 @@ impl Media {
             Utc::now().naive_utc()
         };
 
+        // カメラで付けたレーティングがあれば引き継ぐ
+        let rating = get_rating(origin).await.unwrap_or_default();
+
         // generate meta data
-        let meta = MediaMeta::new(origin.to_string_lossy().to_string(), hashed, date);
+        let meta = MediaMeta::new(origin.to_string_lossy().to_string(), hashed, date)
+            .with_rating(rating)
+            .with_device(get_exif_device(origin).ok())
+            .with_location(sidecar.location.or_else(|| get_exif_location(origin).ok()))
+            .with_description(sidecar.description.clone())
+            .with_sidecar(from_sidecar);
         let media_id = meta.media_id.clone();
-        let _ = meta.save(&mut conn).await?;
 
         // media_id に応じたディレクトリのパス
         let media_directory = data_directory.join(MEDIA_DIRECTORY_NAME).join(&*media_id);
 
         // ディレクトリを掘っておく
-        let _ = create_dir_all(&media_directory).await?;
+        create_dir_all(&media_directory).await?;
 
         // generate thumbnail
+        // プレースホルダをメタ情報と一緒に保存するので、先にサムネイルを作る
         let dest = media_directory.join(THUMB_FILE_NAME);
 
         let source = origin.to_owned();
-        let _ = task::spawn_blocking(move || create_thumb(&source, &dest)).await?;
+        let thumb_option = option.thumb.clone();
+        let thumb = match task::spawn_blocking(move || create_thumb(&source, &dest, &thumb_option))
+            .await?
+        {
+            Ok(thumb) => thumb,
+            Err(e) => {
+                // 壊れているか書き込み中のファイルなので取り込まない
+                let _ = remove_dir_all(&media_directory).await;
+                return Err(e);
+            }
+        };
+        let meta = meta.with_thumb(thumb);
+
+        let mut conn = pool.acquire().await?;
+        if let Err(e) = meta.save(&mut conn).await {
+            // 同じハッシュ値のものを他のワーカーが先に保存した場合など
+            let _ = remove_dir_all(&media_directory).await;
+            return Err(e);
+        }
+        attach_albums(&mut conn, &meta, &sidecar).await?;
 
         Ok(Media { meta })
     }
 
     /// ディレクトリを指定して読み込む
+    /// ファイルごとにジョブを追加して、全て終わるまで `workers` 個のワーカーで処理する
     pub async fn generate_many(
+        pool: &SqlitePool,
         source_directory: &Path,
         data_directory: &Path,
         option: &MediaGenerateOption,
-    ) -> Result<Vec<Self>> {
+        workers: usize,
+    ) -> Result<Vec<Job>> {
         use indicatif::ProgressBar;
-        use tokio_stream::{self as stream, StreamExt};
 
         // source のファイル一覧を取得
-        let entries = get_image_filenames(source_directory);
+        let entries = get_image_filenames(source_directory, &option.scan)
+            .into_iter()
+            .filter_map(|entry| entry.canonicalize().ok())
+            .collect::<Vec<_>>();
+
+        let job_workers = JobWorkers::new(pool.clone(), data_directory, option.clone());
+        job_workers.enqueue(&entries).await?;
+
+        // 残っているジョブの数から進み具合を表示する
+        let total = entries.len() as u64;
+        let pb = ProgressBar::new(total);
+        let drain = job_workers.drain(&entries, workers);
+        tokio::pin!(drain);
+        loop {
+            let unfinished = tokio::select! {
+                result = &mut drain => {
+                    result?;
+                    break;
+                }
+                _ = tokio::time::sleep(PROGRESS_INTERVAL) => job_workers.count_unfinished(&entries).await,
+            };
+            if let Ok(unfinished) = unfinished {
+                pb.set_position(total.saturating_sub(unfinished as u64));
+            }
+        }
+        if let Ok(unfinished) = job_workers.count_unfinished(&entries).await {
+            pb.set_position(total.saturating_sub(unfinished as u64));
+        }
+        pb.finish_at_current_pos();
+
+        let mut conn = pool.acquire().await?;
+        let mut jobs = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            if let Some(job) = Job::get_by_origin(&mut conn, &entry.to_string_lossy()).await? {
+                jobs.push(job);
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// メタ情報、生成したファイルを削除する
+    /// `remove_origin` が `true` の場合はオリジナルのファイルも削除する
+    pub async fn purge(
+        self,
+        conn: &mut SqliteConnection,
+        data_directory: &Path,
+        remove_origin: bool,
+    ) -> Result<()> {
+        use tokio::fs::*;
 
-        let entries = entries
-            .iter()
-            .map(|entry| Media::generate(entry, data_directory, option));
-        let mut medias = Vec::<Media>::with_capacity(entries.len());
-        let mut stream = stream::iter(entries);
+        let media_directory = data_directory
+            .join(MEDIA_DIRECTORY_NAME)
+            .join(&*self.meta.media_id);
+        let origin = self.meta.origin.clone();
 
-        let pb = ProgressBar::new(medias.capacity() as u64);
+        // 先に行を消して、ハッシュ値を再インポートできるようにする
+        self.meta.delete(conn).await?;
 
-        while let Some(entry) = stream.next().await {
-            if let Ok(entry) = entry.await {
-                medias.push(entry);
-                pb.inc(1);
+        if media_directory.is_dir() {
+            remove_dir_all(&media_directory).await?;
+        }
+        if remove_origin {
+            if let Err(e) = remove_file(&origin).await {
+                log::warn!("failed to remove origin {}: {:?}", origin, e);
             }
         }
 
-        Ok(medias)
+        Ok(())
     }
 
     /// サムネイルを取得する
@@ -145,8 +259,29 @@ impl Media {
     }
 }
 
+/// サイドカーのアルバムをタグとして付ける
+async fn attach_albums(
+    conn: &mut SqliteConnection,
+    meta: &MediaMeta,
+    sidecar: &Sidecar,
+) -> Result<()> {
+    if sidecar.albums.is_empty() {
+        return Ok(());
+    }
+    Tag::attach(conn, &[meta.media_id.to_string()], &sidecar.albums).await
+}
+
 /// EXIF から 日付を取得する
-async fn get_exif_date(path: &Path) -> Result<chrono::NaiveDateTime> {
+pub(super) async fn get_exif_date(path: &Path) -> Result<chrono::NaiveDateTime> {
+    let date = get_exif_local_date(path).await?;
+    match Local.from_local_datetime(&date) {
+        chrono::LocalResult::Single(date) => Ok(date.naive_utc()),
+        _ => bail!("ambiguous local date: {}", date),
+    }
+}
+
+/// EXIF の日付を、カメラのローカル時刻のまま取得する
+pub async fn get_exif_local_date(path: &Path) -> Result<chrono::NaiveDateTime> {
     use exif::{In, Reader, Tag};
     use std::fs::File; // ここtoio化したい
     use std::io::BufReader;
@@ -163,14 +298,154 @@ async fn get_exif_date(path: &Path) -> Result<chrono::NaiveDateTime> {
     };
 
     // できればミリ秒までの精度が欲しいけどなあ
-    let date = Local.datetime_from_str(field.as_str(), "%Y-%m-%d %H:%M:%S")?;
-    let date = date.naive_utc();
+    let date = NaiveDateTime::parse_from_str(field.as_str(), "%Y-%m-%d %H:%M:%S")?;
 
     Ok(date)
 }
 
+/// EXIF の Make と Model から撮影した機器の名前を取得する
+pub(super) fn get_exif_device(path: &Path) -> Result<String> {
+    use exif::{In, Tag};
+
+    let exif = read_exif(path)?;
+    let get = |tag| {
+        exif.get_field(tag, In::PRIMARY)
+            .and_then(|field| get_ascii(&field.value))
+    };
+
+    // Model に Make が含まれていることが多いので、その場合は Model だけにする
+    let device = match (get(Tag::Make), get(Tag::Model)) {
+        (Some(make), Some(model)) if model.starts_with(&make) => model,
+        (Some(make), Some(model)) => format!("{} {}", make, model),
+        (None, Some(model)) => model,
+        (Some(make), None) => make,
+        (None, None) => bail!("not found"),
+    };
+
+    Ok(device)
+}
+
+/// EXIF の GPS 情報から緯度と経度を取得する
+pub(super) fn get_exif_location(path: &Path) -> Result<(f64, f64)> {
+    use exif::{In, Tag, Value};
+
+    let exif = read_exif(path)?;
+
+    // 度、分、秒の有理数を度にして、南緯と西経は負にする
+    let get = |tag, ref_tag, negative: &str| -> Option<f64> {
+        let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(values) if values.len() >= 3 => {
+                values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+        let reference = exif
+            .get_field(ref_tag, In::PRIMARY)
+            .and_then(|field| get_ascii(&field.value));
+        if reference.as_deref() == Some(negative) {
+            Some(-degrees)
+        } else {
+            Some(degrees)
+        }
+    };
+
+    match (
+        get(Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+        get(Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+    ) {
+        (Some(latitude), Some(longitude)) if latitude.is_finite() && longitude.is_finite() => {
+            Ok((latitude, longitude))
+        }
+        _ => bail!("not found"),
+    }
+}
+
+fn read_exif(path: &Path) -> Result<exif::Exif> {
+    use exif::Reader;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let file = File::open(path)?;
+    let mut bufreader = BufReader::new(&file);
+    let exif = Reader::new().read_from_container(&mut bufreader)?;
+    Ok(exif)
+}
+
+/// EXIF の ASCII の値を空白を除いた文字列にする
+fn get_ascii(value: &exif::Value) -> Option<String> {
+    match value {
+        exif::Value::Ascii(values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).trim().to_string())
+            .filter(|value| !value.is_empty()),
+        _ => None,
+    }
+}
+
+/// EXIF か XMP からレーティングを取得する
+async fn get_rating(path: &Path) -> Result<u8> {
+    match get_exif_rating(path) {
+        Ok(rating) => Ok(rating),
+        Err(_) => get_xmp_rating(path).await,
+    }
+}
+
+/// EXIF の Rating (0x4746) からレーティングを取得する
+fn get_exif_rating(path: &Path) -> Result<u8> {
+    use exif::{Context, In, Reader, Tag};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    // kamadak-exif には Rating の定義がないので直接指定する
+    const RATING: Tag = Tag(Context::Tiff, 0x4746);
+
+    let file = File::open(path)?;
+    let mut bufreader = BufReader::new(&file);
+    let exif = Reader::new().read_from_container(&mut bufreader)?;
+
+    let rating = match exif
+        .get_field(RATING, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+    {
+        Some(rating) => rating,
+        None => bail!("not found"),
+    };
+
+    Ok(rating.min(MAX_RATING as u32) as u8)
+}
+
+/// XMP の `xmp:Rating` からレーティングを取得する
+/// 拒否 (-1) は 0 として扱う
+async fn get_xmp_rating(path: &Path) -> Result<u8> {
+    use once_cell::sync::Lazy;
+    use regex::bytes::Regex;
+    use tokio::{fs::File, io::AsyncReadExt};
+
+    // XMP は APP1 にあるので先頭だけを読む
+    const XMP_SEARCH_LENGTH: u64 = 1024 * 1024;
+
+    static RATING: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"xmp:Rating(?:="|>)\s*(-?\d+)"#).unwrap());
+
+    let file = File::open(path).await?;
+    let mut buf = vec![];
+    let _ = file.take(XMP_SEARCH_LENGTH).read_to_end(&mut buf).await?;
+
+    let rating = match RATING
+        .captures(&buf)
+        .and_then(|captures| captures.get(1))
+        .and_then(|rating| std::str::from_utf8(rating.as_bytes()).ok())
+        .and_then(|rating| rating.parse::<i32>().ok())
+    {
+        Some(rating) => rating,
+        None => bail!("not found"),
+    };
+
+    Ok(rating.clamp(0, MAX_RATING as i32) as u8)
+}
+
 /// ファイルのメタデータから日付を取得する
-async fn get_file_created_date(path: &Path) -> Result<chrono::NaiveDateTime> {
+pub(super) async fn get_file_created_date(path: &Path) -> Result<chrono::NaiveDateTime> {
     use std::time::UNIX_EPOCH;
     use tokio::fs::File;
 
@@ -192,7 +467,7 @@ async fn get_file_created_date(path: &Path) -> Result<chrono::NaiveDateTime> {
 }
 
 /// パスで与えたファイルのハッシュ値を取得する
-async fn get_file_hash(path: &Path) -> Result<Vec<u8>> {
+pub(super) async fn get_file_hash(path: &Path) -> Result<Vec<u8>> {
     use sha2::{Digest, Sha512};
     use tokio::{fs::File, io::AsyncReadExt};
 
@@ -1,21 +1,113 @@
+use super::{search::update_search_index, thumb::ThumbInfo};
 use anyhow::Result;
 use chrono::{NaiveDateTime, Utc};
-use serde::{Deserialize, Serialize};
-use sqlx::{prelude::*, query_as, types::Json, SqliteConnection};
-use std::{collections::HashMap, ops::Deref};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{prelude::*, query_as, sqlite::SqliteRow, types::Json, SqliteConnection};
+use std::{collections::HashMap, ops::Deref, path::Path, str::FromStr};
 
 /// メディアのアクセスレベル
-#[derive(Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
 #[repr(u32)]
 pub enum MediaVisibility {
+    // デフォルトはプライベートにする
+    #[default]
     Private,
     Public,
 }
 
-impl Default for MediaVisibility {
-    fn default() -> Self {
-        // デフォルトはプライベートにする
-        MediaVisibility::Private
+impl FromStr for MediaVisibility {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "private" => Ok(MediaVisibility::Private),
+            "public" => Ok(MediaVisibility::Public),
+            _ => bail!("unknown visibility: {}", s),
+        }
+    }
+}
+
+/// 最大のレーティング
+pub const MAX_RATING: u8 = 5;
+
+/// カラーラベル
+#[derive(Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[repr(u32)]
+pub enum ColorLabel {
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+    Gray,
+}
+
+impl FromStr for ColorLabel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "red" => Ok(ColorLabel::Red),
+            "orange" => Ok(ColorLabel::Orange),
+            "yellow" => Ok(ColorLabel::Yellow),
+            "green" => Ok(ColorLabel::Green),
+            "blue" => Ok(ColorLabel::Blue),
+            "purple" => Ok(ColorLabel::Purple),
+            "gray" | "grey" => Ok(ColorLabel::Gray),
+            _ => bail!("unknown label: {}", s),
+        }
+    }
+}
+
+/// `0xRRGGBB` の形式で持つ色
+#[derive(Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(transparent)]
+pub struct Color(u32);
+
+impl Color {
+    pub fn new(r: u8, g: u8, b: u8) -> Self {
+        Color(((r as u32) << 16) | ((g as u32) << 8) | b as u32)
+    }
+
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        ((self.0 >> 16) as u8, (self.0 >> 8) as u8, self.0 as u8)
+    }
+}
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    /// `#RRGGBB` か `RRGGBB` の形式をパースする
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        ensure!(
+            hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            "color must be #RRGGBB: {}",
+            s
+        );
+        Ok(Color(u32::from_str_radix(hex, 16)?))
+    }
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:06x}", self.0)
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -45,94 +137,112 @@ impl Deref for MediaId {
     }
 }
 
-/// MediaId を検索するためのフィルタ
-#[derive(Deserialize, Debug)]
-pub struct IdsFilter {
-    /// 検索開始する日付開始地点をミリ秒で指定する
-    pub begin: Option<i64>,
+/// メタファイルの構造
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct MediaMeta {
+    pub media_id: MediaId,
+    pub origin: String,
+    pub visibility: MediaVisibility,
+    pub date: NaiveDateTime,
+    #[serde(serialize_with = "serialize_hex")]
+    pub hashed: Vec<u8>,
+    pub attributes: Option<Json<HashMap<String, String>>>,
+    /// ゴミ箱に入れた日時
+    pub deleted_at: Option<NaiveDateTime>,
+    /// 0 から 5 までのレーティング、0 は未評価
+    pub rating: u8,
+    pub favorite: bool,
+    pub label: Option<ColorLabel>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// 読み込み中に表示するプレースホルダ
+    pub blurhash: Option<String>,
+    /// 幅 / 高さ
+    pub aspect_ratio: Option<f64>,
+    /// 最も多く使われている色
+    pub dominant_color: Option<Color>,
+    /// 多く使われている順に並べた色
+    pub palette: Option<Json<Vec<Color>>>,
+    /// 撮影した機器の名前
+    pub device: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// オリジナルのファイルが見つからなくなった日時
+    pub missing_at: Option<NaiveDateTime>,
+    /// 画像に添えられた JSON から取り込んだ
+    pub sidecar: bool,
+}
 
-    /// 検索開始する日付終了地点をミリ秒で指定する
-    pub end: Option<i64>,
+/// タグ名の JSON の配列を返す列
+pub(super) const TAGS_COLUMN: &str = r#"
+    coalesce((
+        select json_group_array(tags.name) from (
+            select tags.name from media_tags
+            join tags using (tag_id)
+            where media_tags.media_id = metas.media_id
+            order by tags.name
+        ) tags
+    ), '[]') as tags
+"#;
 
-    /// 取得件数を指定する
-    pub count: Option<u64>,
+/// タグ付きのメタ情報
+/// `select metas.*, TAGS_COLUMN from metas` で一度に取得する
+#[derive(Debug, Clone)]
+pub struct MediaMetaWithTags {
+    pub meta: MediaMeta,
+    pub tags: Vec<String>,
 }
 
-impl IdsFilter {
-    /// フィルタをタプルに展開する
-    /// `begin` が `None` の場合は現在時刻, `end` が `None` の場合は `0`, `count` が `None` の場合は `100` をデフォルトに使用する
-    pub fn build(self) -> (NaiveDateTime, NaiveDateTime, u64) {
-        fn to_naive_datetime(milli: i64) -> NaiveDateTime {
-            NaiveDateTime::from_timestamp(milli / 1000, (milli % 1000 * 1000 * 1000) as u32)
-        }
-        let begin = self
-            .begin
-            .map(to_naive_datetime)
-            .unwrap_or_else(|| Utc::now().naive_utc());
-
-        let end = self
-            .end
-            .map(to_naive_datetime)
-            .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
-        let count = self.count.unwrap_or(100);
-        (begin, end, count)
+impl<'r> FromRow<'r, SqliteRow> for MediaMetaWithTags {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        let meta = MediaMeta::from_row(row)?;
+        let tags: Json<Vec<String>> = row.try_get("tags")?;
+        Ok(MediaMetaWithTags { meta, tags: tags.0 })
     }
 }
 
-impl MediaId {
-    /// `MediaId` を日付で降順に検索して取得する関数
-    /// 成功すると `MediaId` のリストと最後の要素の日付を返す
-    pub async fn filter(
+impl MediaMetaWithTags {
+    /// 複数のメディアのメタ情報をまとめて取得する
+    /// 存在しないものは含まれず、順序は `media_ids` に従う
+    pub async fn list_by_ids(
         conn: &mut SqliteConnection,
-        option: IdsFilter,
-        include_private: bool,
-    ) -> Result<(Vec<MediaId>, NaiveDateTime)> {
-        use sqlx::*;
-
-        let (begin, end, count) = option.build();
-
-        let visibility = if include_private {
-            MediaVisibility::Private
-        } else {
-            MediaVisibility::Public
-        };
+        media_ids: &[String],
+    ) -> Result<Vec<Self>> {
+        if media_ids.is_empty() {
+            return Ok(vec![]);
+        }
 
-        let ids: Vec<MediaIdWithDateRow> = query_as(
-            r#"
-            select media_id, date, visibility from metas
-            where date between ? and ? and visibility = ?
-            order by date desc
-            limit ?
-            "#,
-        )
-        .bind(end.to_string())
-        .bind(begin.to_string())
-        .bind(visibility)
-        .bind(count as i64)
-        .fetch_all(conn)
-        .await?;
-        let last = ids.last().map(|row| row.date).unwrap_or(end);
-        let ids: Vec<MediaId> = ids.into_iter().map(|row| row.media_id).collect();
+        let sql = format!(
+            "select metas.*, {} from metas where media_id in ({})",
+            TAGS_COLUMN,
+            vec!["?"; media_ids.len()].join(", ")
+        );
+        let mut query = query_as(&sql);
+        for media_id in media_ids {
+            query = query.bind(media_id);
+        }
+        let metas: Vec<Self> = query.fetch_all(conn).await?;
 
-        Ok((ids, last))
+        let mut metas = metas
+            .into_iter()
+            .map(|meta| (meta.meta.media_id.to_string(), meta))
+            .collect::<HashMap<_, _>>();
+        Ok(media_ids
+            .iter()
+            .flat_map(|media_id| metas.remove(media_id))
+            .collect())
     }
 }
 
-/// メタファイルの構造
-#[derive(FromRow, Debug, Clone)]
-pub struct MediaMeta {
-    pub media_id: MediaId,
-    pub origin: String,
-    pub visibility: MediaVisibility,
-    pub date: NaiveDateTime,
-    pub hashed: Vec<u8>,
-    pub attributes: Option<Json<HashMap<String, String>>>,
-}
-
-#[derive(FromRow, Debug, Clone)]
-struct MediaIdWithDateRow {
-    pub media_id: MediaId,
-    pub date: NaiveDateTime,
+/// ハッシュ値を16進数の文字列として出力する
+fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    serializer.serialize_str(&hex)
 }
 
 impl MediaMeta {
@@ -144,6 +254,80 @@ impl MediaMeta {
             media_id: MediaId::new(),
             visibility: Default::default(),
             attributes: Default::default(),
+            deleted_at: None,
+            rating: 0,
+            favorite: false,
+            label: None,
+            title: None,
+            description: None,
+            width: None,
+            height: None,
+            blurhash: None,
+            aspect_ratio: None,
+            dominant_color: None,
+            palette: None,
+            device: None,
+            latitude: None,
+            longitude: None,
+            missing_at: None,
+            sidecar: false,
+        }
+    }
+
+    pub fn with_dimensions(self, width: u32, height: u32) -> Self {
+        let aspect_ratio = if height > 0 {
+            Some(width as f64 / height as f64)
+        } else {
+            None
+        };
+        MediaMeta {
+            width: Some(width),
+            height: Some(height),
+            aspect_ratio,
+            ..self
+        }
+    }
+
+    /// サムネイルを作る際に得られた情報を反映する
+    pub fn with_thumb(self, thumb: ThumbInfo) -> Self {
+        MediaMeta {
+            blurhash: thumb.blurhash,
+            dominant_color: thumb.palette.first().copied(),
+            palette: Some(thumb.palette)
+                .filter(|palette| !palette.is_empty())
+                .map(Json),
+            ..self.with_dimensions(thumb.width, thumb.height)
+        }
+    }
+
+    pub fn with_device(self, device: Option<String>) -> Self {
+        MediaMeta { device, ..self }
+    }
+
+    pub fn with_description(self, description: Option<String>) -> Self {
+        MediaMeta {
+            description,
+            ..self
+        }
+    }
+
+    /// 緯度と経度を設定する
+    pub fn with_location(self, location: Option<(f64, f64)>) -> Self {
+        MediaMeta {
+            latitude: location.map(|(latitude, _)| latitude),
+            longitude: location.map(|(_, longitude)| longitude),
+            ..self
+        }
+    }
+
+    pub fn with_sidecar(self, sidecar: bool) -> Self {
+        MediaMeta { sidecar, ..self }
+    }
+
+    pub fn with_rating(self, rating: u8) -> Self {
+        MediaMeta {
+            rating: rating.min(MAX_RATING),
+            ..self
         }
     }
 
@@ -165,11 +349,10 @@ impl MediaMeta {
 
     pub async fn save(&self, conn: &mut SqliteConnection) -> Result<()> {
         // とりあえず重複は考えない
-        let _ = query_as::<_, MediaMeta>(
+        let _ = sqlx::query(
             r#"
-        insert into metas (media_id, origin, visibility, date, hashed, attributes)
-        values ($1, $2, $3, $4, $5, $6)
-        returning *
+        insert into metas (media_id, origin, visibility, date, hashed, attributes, rating, favorite, label, title, description, width, height, blurhash, aspect_ratio, dominant_color, palette, device, latitude, longitude, sidecar)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21)
         "#,
         )
         .bind(self.media_id.to_string())
@@ -178,9 +361,26 @@ impl MediaMeta {
         .bind(self.date)
         .bind(&self.hashed)
         .bind(self.attributes.as_ref())
-        .fetch_one(conn)
+        .bind(self.rating)
+        .bind(self.favorite)
+        .bind(self.label)
+        .bind(self.title.as_ref())
+        .bind(self.description.as_ref())
+        .bind(self.width)
+        .bind(self.height)
+        .bind(self.blurhash.as_ref())
+        .bind(self.aspect_ratio)
+        .bind(self.dominant_color)
+        .bind(self.palette.as_ref())
+        .bind(self.device.as_ref())
+        .bind(self.latitude)
+        .bind(self.longitude)
+        .bind(self.sidecar)
+        .execute(&mut *conn)
         .await?;
 
+        update_search_index(conn, &self.media_id).await?;
+
         Ok(())
     }
 
@@ -192,6 +392,288 @@ impl MediaMeta {
         Ok(meta)
     }
 
+    /// ゴミ箱に入っているものも含めて全て取得する
+    pub async fn list_all(conn: &mut SqliteConnection) -> Result<Vec<Self>> {
+        let metas = query_as("select * from metas order by date desc")
+            .fetch_all(conn)
+            .await?;
+        Ok(metas)
+    }
+
+    /// 日付の範囲 (`from` 以上 `to` 未満) で取得する
+    pub async fn list_by_date_range(
+        conn: &mut SqliteConnection,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<Self>> {
+        let metas = query_as(
+            r#"
+            select * from metas
+            where date >= ? and date < ?
+            order by date desc
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(conn)
+        .await?;
+        Ok(metas)
+    }
+
+    /// オリジナルのファイルが `dir` 以下にあるものを取得する
+    pub async fn list_by_origin_dir(conn: &mut SqliteConnection, dir: &Path) -> Result<Vec<Self>> {
+        // origin はフルパスで保存しているので、前方一致で探す
+        let dir = dir.canonicalize()?;
+        let prefix = format!("{}/", dir.to_string_lossy().trim_end_matches('/'));
+        let metas = query_as(
+            r#"
+            select * from metas
+            where substr(origin, 1, length(?1)) = ?1
+            order by date desc
+            "#,
+        )
+        .bind(prefix)
+        .fetch_all(conn)
+        .await?;
+        Ok(metas)
+    }
+
+    /// オリジナルから抽出する値を更新する
+    /// 公開範囲や属性などのユーザが編集する値は変更しない
+    pub async fn update_extracted(&self, conn: &mut SqliteConnection) -> Result<()> {
+        let _ = sqlx::query(
+            r#"
+            update metas
+            set date = ?, device = ?, latitude = ?, longitude = ?, width = ?, height = ?, aspect_ratio = ?
+            where media_id = ?
+            "#,
+        )
+        .bind(self.date)
+        .bind(self.device.as_ref())
+        .bind(self.latitude)
+        .bind(self.longitude)
+        .bind(self.width)
+        .bind(self.height)
+        .bind(self.aspect_ratio)
+        .bind(self.media_id.to_string())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// 公開範囲を更新する
+    pub async fn update_visibility(
+        &mut self,
+        conn: &mut SqliteConnection,
+        visibility: MediaVisibility,
+    ) -> Result<()> {
+        let _ = sqlx::query("update metas set visibility = ? where media_id = ?")
+            .bind(visibility)
+            .bind(self.media_id.to_string())
+            .execute(conn)
+            .await?;
+        self.visibility = visibility;
+        Ok(())
+    }
+
+    /// レーティングを更新する
+    pub async fn update_rating(&mut self, conn: &mut SqliteConnection, rating: u8) -> Result<()> {
+        ensure!(
+            rating <= MAX_RATING,
+            "rating must be 0 to {}: {}",
+            MAX_RATING,
+            rating
+        );
+        let _ = sqlx::query("update metas set rating = ? where media_id = ?")
+            .bind(rating)
+            .bind(self.media_id.to_string())
+            .execute(conn)
+            .await?;
+        self.rating = rating;
+        Ok(())
+    }
+
+    /// お気に入りを更新する
+    pub async fn update_favorite(
+        &mut self,
+        conn: &mut SqliteConnection,
+        favorite: bool,
+    ) -> Result<()> {
+        let _ = sqlx::query("update metas set favorite = ? where media_id = ?")
+            .bind(favorite)
+            .bind(self.media_id.to_string())
+            .execute(conn)
+            .await?;
+        self.favorite = favorite;
+        Ok(())
+    }
+
+    /// カラーラベルを更新する、`None` の場合はラベルを外す
+    pub async fn update_label(
+        &mut self,
+        conn: &mut SqliteConnection,
+        label: Option<ColorLabel>,
+    ) -> Result<()> {
+        let _ = sqlx::query("update metas set label = ? where media_id = ?")
+            .bind(label)
+            .bind(self.media_id.to_string())
+            .execute(conn)
+            .await?;
+        self.label = label;
+        Ok(())
+    }
+
+    /// 属性をひとつ設定する
+    /// 同じキーが既にある場合は上書きする
+    pub async fn set_attribute(
+        &mut self,
+        conn: &mut SqliteConnection,
+        key: String,
+        value: String,
+    ) -> Result<()> {
+        let mut attributes = self.attributes.clone().unwrap_or_default();
+        attributes.insert(key, value);
+        let _ = sqlx::query("update metas set attributes = ? where media_id = ?")
+            .bind(&attributes)
+            .bind(self.media_id.to_string())
+            .execute(&mut *conn)
+            .await?;
+        update_search_index(conn, &self.media_id).await?;
+        self.attributes = Some(attributes);
+        Ok(())
+    }
+
+    /// タイトルを更新する、`None` の場合は消す
+    pub async fn update_title(
+        &mut self,
+        conn: &mut SqliteConnection,
+        title: Option<String>,
+    ) -> Result<()> {
+        let _ = sqlx::query("update metas set title = ? where media_id = ?")
+            .bind(title.as_ref())
+            .bind(self.media_id.to_string())
+            .execute(&mut *conn)
+            .await?;
+        update_search_index(conn, &self.media_id).await?;
+        self.title = title;
+        Ok(())
+    }
+
+    /// 説明を更新する、`None` の場合は消す
+    pub async fn update_description(
+        &mut self,
+        conn: &mut SqliteConnection,
+        description: Option<String>,
+    ) -> Result<()> {
+        let _ = sqlx::query("update metas set description = ? where media_id = ?")
+            .bind(description.as_ref())
+            .bind(self.media_id.to_string())
+            .execute(&mut *conn)
+            .await?;
+        update_search_index(conn, &self.media_id).await?;
+        self.description = description;
+        Ok(())
+    }
+
+    /// ゴミ箱に入っているかどうか
+    pub fn is_trashed(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// ゴミ箱に入れる
+    pub async fn trash(&mut self, conn: &mut SqliteConnection) -> Result<()> {
+        let deleted_at = Utc::now().naive_utc();
+        let _ = sqlx::query("update metas set deleted_at = ? where media_id = ?")
+            .bind(deleted_at)
+            .bind(self.media_id.to_string())
+            .execute(conn)
+            .await?;
+        self.deleted_at = Some(deleted_at);
+        Ok(())
+    }
+
+    /// ゴミ箱から戻す
+    pub async fn restore(&mut self, conn: &mut SqliteConnection) -> Result<()> {
+        let _ = sqlx::query("update metas set deleted_at = null where media_id = ?")
+            .bind(self.media_id.to_string())
+            .execute(conn)
+            .await?;
+        self.deleted_at = None;
+        Ok(())
+    }
+
+    /// `before` より前にゴミ箱に入れたものを取得する
+    pub async fn list_trashed_before(
+        conn: &mut SqliteConnection,
+        before: NaiveDateTime,
+    ) -> Result<Vec<Self>> {
+        let metas = query_as(
+            r#"
+            select * from metas
+            where deleted_at is not null and deleted_at < ?
+            order by deleted_at asc
+            "#,
+        )
+        .bind(before)
+        .fetch_all(conn)
+        .await?;
+        Ok(metas)
+    }
+
+    /// データベースから削除する
+    pub async fn delete(self, conn: &mut SqliteConnection) -> Result<()> {
+        let _ = sqlx::query("delete from metas where media_id = ?")
+            .bind(self.media_id.to_string())
+            .execute(&mut *conn)
+            .await?;
+        update_search_index(conn, &self.media_id).await?;
+        Ok(())
+    }
+
+    /// オリジナルのパスを更新して、見つからない状態を解除する
+    pub async fn update_origin(&mut self, conn: &mut SqliteConnection, origin: &str) -> Result<()> {
+        let _ = sqlx::query("update metas set origin = ?, missing_at = null where media_id = ?")
+            .bind(origin)
+            .bind(self.media_id.to_string())
+            .execute(&mut *conn)
+            .await?;
+        update_search_index(conn, &self.media_id).await?;
+        self.origin = origin.to_string();
+        self.missing_at = None;
+        Ok(())
+    }
+
+    /// オリジナルが見つからなくなったことを記録する
+    pub async fn mark_missing(&mut self, conn: &mut SqliteConnection) -> Result<()> {
+        let missing_at = Utc::now().naive_utc();
+        let _ = sqlx::query("update metas set missing_at = ? where media_id = ?")
+            .bind(missing_at)
+            .bind(self.media_id.to_string())
+            .execute(conn)
+            .await?;
+        self.missing_at = Some(missing_at);
+        Ok(())
+    }
+
+    /// オリジナルが `path` か `path` 以下にあるものを取得する
+    /// 削除や移動されたパスはファイルかディレクトリか分からないので、両方を探す
+    pub async fn list_by_origin_path(conn: &mut SqliteConnection, path: &Path) -> Result<Vec<Self>> {
+        let path = path.to_string_lossy();
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let metas = query_as(
+            r#"
+            select * from metas
+            where origin = ?1 or substr(origin, 1, length(?2)) = ?2
+            order by date desc
+            "#,
+        )
+        .bind(path.to_string())
+        .bind(prefix)
+        .fetch_all(conn)
+        .await?;
+        Ok(metas)
+    }
+
     pub async fn get_by_hashed(conn: &mut SqliteConnection, hashed: &[u8]) -> Result<Self> {
         let meta = query_as("select * from metas where hashed = $1")
             .bind(hashed)
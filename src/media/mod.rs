@@ -1,7 +1,26 @@
 pub mod common;
+mod filter;
+mod job;
+#[allow(clippy::module_inception)]
 mod media;
 mod meta;
+mod quarantine;
+mod reindex;
+mod scan;
+mod search;
+mod sidecar;
+mod tag;
 mod thumb;
+mod timeline;
+mod upload;
 
+pub use filter::*;
+pub use job::*;
 pub use meta::*;
 pub use media::*;
+pub use reindex::*;
+pub use scan::*;
+pub use tag::*;
+pub use thumb::ThumbOption;
+pub use timeline::*;
+pub use upload::*;
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use sqlx::{prelude::*, query_as, SqliteConnection};
+
+/// 読み込めなかったため取り込みを保留しているファイル
+/// ファイルが変更されるまでは取り込み直さない
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct Quarantine {
+    pub origin: String,
+    pub size: i64,
+    pub modified: NaiveDateTime,
+    pub error: String,
+    pub quarantined_at: NaiveDateTime,
+}
+
+impl Quarantine {
+    pub async fn get(conn: &mut SqliteConnection, origin: &str) -> Result<Option<Self>> {
+        let quarantine = query_as("select * from quarantine where origin = ?")
+            .bind(origin)
+            .fetch_optional(conn)
+            .await?;
+        Ok(quarantine)
+    }
+
+    /// 隔離した時からファイルが変わっていないかどうか
+    pub fn is_unchanged(&self, size: u64, modified: NaiveDateTime) -> bool {
+        self.size == size as i64 && self.modified == modified
+    }
+
+    /// 隔離する、既に隔離していた場合は上書きする
+    pub async fn put(
+        conn: &mut SqliteConnection,
+        origin: &str,
+        size: u64,
+        modified: NaiveDateTime,
+        error: &str,
+    ) -> Result<()> {
+        let _ = sqlx::query(
+            r#"
+            insert or replace into quarantine (origin, size, modified, error, quarantined_at)
+            values (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(origin)
+        .bind(size as i64)
+        .bind(modified)
+        .bind(error)
+        .bind(Utc::now().naive_utc())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// 取り込めたので隔離を解除する
+    pub async fn remove(conn: &mut SqliteConnection, origin: &str) -> Result<()> {
+        let _ = sqlx::query("delete from quarantine where origin = ?")
+            .bind(origin)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
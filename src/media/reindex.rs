@@ -0,0 +1,124 @@
+use super::{media::*, meta::*, sidecar::Sidecar};
+use anyhow::Result;
+use std::{fmt::Display, path::Path, str::FromStr};
+
+/// オリジナルから抽出し直すフィールド
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReindexField {
+    Date,
+    /// 撮影した機器の名前
+    Exif,
+    /// 緯度と経度
+    Gps,
+    /// 幅、高さ、アスペクト比
+    Dimensions,
+}
+
+impl ReindexField {
+    pub const ALL: &'static [ReindexField] = &[
+        ReindexField::Date,
+        ReindexField::Exif,
+        ReindexField::Gps,
+        ReindexField::Dimensions,
+    ];
+}
+
+impl FromStr for ReindexField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "date" => Ok(ReindexField::Date),
+            "exif" => Ok(ReindexField::Exif),
+            "gps" => Ok(ReindexField::Gps),
+            "dimensions" => Ok(ReindexField::Dimensions),
+            _ => bail!("unknown field: {}", s),
+        }
+    }
+}
+
+/// 抽出し直して変わった値
+#[derive(Debug, Clone)]
+pub struct ReindexChange {
+    pub column: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+impl MediaMeta {
+    /// オリジナルから `fields` を抽出し直したメタ情報と変更点を返す
+    /// データベースは更新しないので、`update_extracted` で保存する
+    pub async fn reindexed(&self, fields: &[ReindexField]) -> Result<(Self, Vec<ReindexChange>)> {
+        let origin = Path::new(&self.origin);
+        ensure!(origin.is_file(), "origin not found: {}", self.origin);
+
+        // JSON から取り込んだものは、取り込んだ時と同じように JSON を EXIF より優先する
+        let sidecar = if self.sidecar {
+            Sidecar::find(origin).await.ok().flatten()
+        } else {
+            None
+        };
+        let sidecar = sidecar.unwrap_or_default();
+
+        let mut meta = self.clone();
+        for field in fields {
+            meta = match field {
+                // 取れない場合はインポートした時の値を残す
+                ReindexField::Date => {
+                    let date = match sidecar.date {
+                        Some(date) => Ok(date),
+                        None => get_exif_date(origin).await,
+                    };
+                    match date {
+                        Ok(date) => MediaMeta { date, ..meta },
+                        Err(_) => meta,
+                    }
+                }
+                ReindexField::Exif => match get_exif_device(origin) {
+                    Ok(device) => meta.with_device(Some(device)),
+                    Err(_) => meta,
+                },
+                ReindexField::Gps => {
+                    let location = match sidecar.location {
+                        Some(location) => Ok(location),
+                        None => get_exif_location(origin),
+                    };
+                    match location {
+                        Ok(location) => meta.with_location(Some(location)),
+                        Err(_) => meta,
+                    }
+                }
+                ReindexField::Dimensions => {
+                    let (width, height) = image::image_dimensions(origin)?;
+                    meta.with_dimensions(width, height)
+                }
+            };
+        }
+
+        let mut changes = vec![];
+        let mut compare = |column, before: String, after: String| {
+            if before != after {
+                changes.push(ReindexChange {
+                    column,
+                    before,
+                    after,
+                });
+            }
+        };
+        compare("date", self.date.to_string(), meta.date.to_string());
+        compare("device", show(&self.device), show(&meta.device));
+        compare("latitude", show(&self.latitude), show(&meta.latitude));
+        compare("longitude", show(&self.longitude), show(&meta.longitude));
+        compare("width", show(&self.width), show(&meta.width));
+        compare("height", show(&self.height), show(&meta.height));
+
+        Ok((meta, changes))
+    }
+}
+
+fn show<T: Display>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".to_string(),
+    }
+}
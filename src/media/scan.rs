@@ -0,0 +1,328 @@
+use super::common::is_target;
+use anyhow::Result;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// 探す深さのデフォルト
+pub const DEFAULT_MAX_DEPTH: u32 = 5;
+
+/// デフォルトで除くパターン、NAS が作るサムネイルやゴミ箱
+pub const DEFAULT_EXCLUDE: [&str; 4] = [
+    "**/@eaDir",
+    "**/#recycle",
+    "**/$RECYCLE.BIN",
+    "**/System Volume Information",
+];
+
+/// ディレクトリからファイルを探す際のルール
+/// パターンは探し始めたディレクトリからの相対パスと比べる
+#[derive(Debug, Clone)]
+pub struct ScanOption {
+    /// 何階層まで探すか、`None` の場合は無制限
+    pub max_depth: Option<u32>,
+    /// 指定した場合は、どれかに一致するファイルだけを対象にする
+    include: Option<GlobSet>,
+    /// 一致するファイルとディレクトリを除く
+    exclude: GlobSet,
+    /// `.` で始まるファイルとディレクトリを除く (`.Trash`, `._*` など)
+    pub skip_hidden: bool,
+    /// シンボリックリンクを辿るか
+    pub follow_symlinks: bool,
+}
+
+impl Default for ScanOption {
+    fn default() -> Self {
+        let exclude = DEFAULT_EXCLUDE.map(String::from);
+        ScanOption::new(Some(DEFAULT_MAX_DEPTH), &[], &exclude, true, true).unwrap()
+    }
+}
+
+impl ScanOption {
+    pub fn new(
+        max_depth: Option<u32>,
+        include: &[String],
+        exclude: &[String],
+        skip_hidden: bool,
+        follow_symlinks: bool,
+    ) -> Result<Self> {
+        let include = match include {
+            [] => None,
+            include => Some(glob_set(include)?),
+        };
+        Ok(ScanOption {
+            max_depth,
+            include,
+            exclude: glob_set(exclude)?,
+            skip_hidden,
+            follow_symlinks,
+        })
+    }
+
+    /// `root` 以下の対象のファイルを、`root` から辿ったパスで返す
+    /// シンボリックリンクを辿った場合もリンク先のパスにはしないので、`matches` で確かめられる
+    pub fn scan(&self, root: &Path) -> Vec<PathBuf> {
+        self.scan_dir(root, root)
+    }
+
+    /// `root` 以下にある `dir` の中の対象のファイルを、`root` から辿ったパスで返す
+    pub fn scan_dir(&self, root: &Path, dir: &Path) -> Vec<PathBuf> {
+        // 既に除くディレクトリなら探さない
+        if dir != root && !self.is_allowed_path(root, dir) {
+            return vec![];
+        }
+        let mut visited = HashSet::new();
+        let mut files = vec![];
+        self.walk(root, dir, &mut visited, &mut files);
+        files
+    }
+
+    /// `root` 以下で見つけたファイルが対象か確かめる
+    pub fn matches(&self, root: &Path, path: &Path) -> bool {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        if !self.within_depth(relative) {
+            return false;
+        }
+        is_target(path) && self.is_allowed_path(root, path) && self.is_included(relative)
+    }
+
+    fn walk(
+        &self,
+        root: &Path,
+        dir: &Path,
+        visited: &mut HashSet<PathBuf>,
+        files: &mut Vec<PathBuf>,
+    ) {
+        // ディレクトリとファイルを実体のパスで覚えておき、リンクでループしている場合や
+        // 同じファイルにリンクから辿り着いた場合は二度返さない
+        let real = match dir.canonicalize() {
+            Ok(real) => real,
+            Err(_) => return,
+        };
+        if !visited.insert(real) {
+            log::warn!("skip visited directory: {:?}", dir);
+            return;
+        }
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let relative = match path.strip_prefix(root) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            if !self.within_depth(relative) || !self.is_allowed(&path, relative) {
+                continue;
+            }
+            if path.is_dir() {
+                self.walk(root, &path, visited, files);
+            } else if is_target(&path) && self.is_included(relative) {
+                if let Ok(real) = path.canonicalize() {
+                    if visited.insert(real) {
+                        files.push(path);
+                    }
+                }
+            }
+        }
+    }
+
+    fn within_depth(&self, relative: &Path) -> bool {
+        match self.max_depth {
+            Some(max_depth) => relative.components().count() <= max_depth as usize,
+            None => true,
+        }
+    }
+
+    /// `path` と、`root` までの親のディレクトリが除くものでないか確かめる
+    fn is_allowed_path(&self, root: &Path, path: &Path) -> bool {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let mut current = root.to_path_buf();
+        let mut current_relative = PathBuf::new();
+        relative.components().all(|component| {
+            current.push(component);
+            current_relative.push(component);
+            self.is_allowed(&current, &current_relative)
+        })
+    }
+
+    fn is_allowed(&self, path: &Path, relative: &Path) -> bool {
+        let hidden = path
+            .file_name()
+            .map(|name| name.to_string_lossy().starts_with('.'))
+            .unwrap_or(false);
+        if self.skip_hidden && hidden {
+            return false;
+        }
+        if self.exclude.is_match(relative) {
+            return false;
+        }
+        if !self.follow_symlinks {
+            let symlink = path
+                .symlink_metadata()
+                .map(|metadata| metadata.file_type().is_symlink())
+                .unwrap_or(false);
+            if symlink {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn is_included(&self, relative: &Path) -> bool {
+        match &self.include {
+            Some(include) => include.is_match(relative),
+            None => true,
+        }
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).map_err(|e| anyhow!("invalid pattern {}: {}", pattern, e))?;
+        let _ = builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// テストごとに一時ディレクトリを作り、`files` を空のファイルとして置く
+    struct TempTree(PathBuf);
+
+    impl TempTree {
+        fn new(files: &[&str]) -> Self {
+            let root = std::env::temp_dir().join(format!("miruku-scan-{}", uuid::Uuid::new_v4()));
+            for file in files {
+                let path = root.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, b"").unwrap();
+            }
+            std::fs::create_dir_all(&root).unwrap();
+            TempTree(root)
+        }
+
+        /// `root` からの相対パスにして並べる
+        fn scan(&self, option: &ScanOption) -> Vec<String> {
+            let mut files = option
+                .scan(&self.0)
+                .into_iter()
+                .map(|path| {
+                    let relative = path.strip_prefix(&self.0).unwrap();
+                    relative.to_string_lossy().to_string()
+                })
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TempTree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn option(max_depth: Option<u32>, include: &[&str], exclude: &[&str]) -> ScanOption {
+        let include = include.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let exclude = exclude.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        ScanOption::new(max_depth, &include, &exclude, true, true).unwrap()
+    }
+
+    #[test]
+    fn within_depth() {
+        let path = Path::new("a/b/c.jpg");
+        assert!(!option(Some(2), &[], &[]).within_depth(path));
+        assert!(option(Some(3), &[], &[]).within_depth(path));
+        assert!(option(None, &[], &[]).within_depth(path));
+
+        let tree = TempTree::new(&["a.jpg", "x/b.jpg", "x/y/c.jpg"]);
+        assert_eq!(
+            tree.scan(&option(Some(2), &[], &[])),
+            vec!["a.jpg", "x/b.jpg"]
+        );
+        assert_eq!(
+            tree.scan(&option(None, &[], &[])),
+            vec!["a.jpg", "x/b.jpg", "x/y/c.jpg"]
+        );
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let tree = TempTree::new(&[
+            "keep/a.jpg",
+            "keep/skip/b.jpg",
+            "other/c.jpg",
+            "keep/d.txt",
+            "@eaDir/e.jpg",
+        ]);
+        assert_eq!(
+            tree.scan(&option(None, &["keep/**"], &["**/skip"])),
+            vec!["keep/a.jpg"]
+        );
+        assert_eq!(
+            tree.scan(&ScanOption::default()),
+            vec!["keep/a.jpg", "keep/skip/b.jpg", "other/c.jpg"]
+        );
+        assert!(ScanOption::new(None, &["[".to_string()], &[], true, true).is_err());
+    }
+
+    #[test]
+    fn hidden() {
+        let tree = TempTree::new(&["a.jpg", "._a.jpg", ".Trash/b.jpg"]);
+        assert_eq!(tree.scan(&option(None, &[], &[])), vec!["a.jpg"]);
+
+        let option = ScanOption::new(None, &[], &[], false, true).unwrap();
+        assert_eq!(tree.scan(&option), vec![".Trash/b.jpg", "._a.jpg", "a.jpg"]);
+    }
+
+    #[test]
+    fn excluded_ancestors() {
+        let tree = TempTree::new(&["skip/deep/a.jpg", "keep/deep/b.jpg"]);
+        let option = option(None, &[], &["skip"]);
+        let root = &tree.0;
+
+        // 親のディレクトリが除くものなら、その下のファイルも対象にしない
+        assert!(!option.matches(root, &root.join("skip/deep/a.jpg")));
+        assert!(option.matches(root, &root.join("keep/deep/b.jpg")));
+        assert!(!option.is_allowed_path(root, &root.join("skip/deep")));
+        assert!(option.scan_dir(root, &root.join("skip/deep")).is_empty());
+        assert_eq!(option.scan_dir(root, &root.join("keep/deep")).len(), 1);
+
+        // `root` の外のパスは対象にしない
+        assert!(!option.matches(root, Path::new("/elsewhere/keep/deep/b.jpg")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop_and_duplicates() {
+        use std::os::unix::fs::symlink;
+
+        let tree = TempTree::new(&["a/x.jpg"]);
+        let root = &tree.0;
+        // 親に戻るリンクと、同じファイルへのリンク
+        symlink(root, root.join("a/loop")).unwrap();
+        symlink(root.join("a/x.jpg"), root.join("link.jpg")).unwrap();
+
+        let files = tree.scan(&option(None, &[], &[]));
+        assert_eq!(files.len(), 1, "{:?}", files);
+        assert!(files[0] == "a/x.jpg" || files[0] == "link.jpg");
+
+        let option = ScanOption::new(None, &[], &[], true, false).unwrap();
+        assert_eq!(tree.scan(&option), vec!["a/x.jpg"]);
+    }
+}
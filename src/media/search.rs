@@ -0,0 +1,48 @@
+use anyhow::Result;
+use sqlx::SqliteConnection;
+
+/// 全文検索のインデックスを更新する
+/// `metas` の行がない場合はインデックスから削除するだけになる
+pub async fn update_search_index(conn: &mut SqliteConnection, media_id: &str) -> Result<()> {
+    let _ = sqlx::query("delete from metas_fts where media_id = ?")
+        .bind(media_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let _ = sqlx::query(
+        r#"
+        insert into metas_fts (media_id, origin, title, description, tags, attributes)
+        select
+            media_id,
+            origin,
+            coalesce(title, ''),
+            coalesce(description, ''),
+            coalesce((
+                select group_concat(tags.name, ' ') from media_tags
+                join tags using (tag_id)
+                where media_tags.media_id = metas.media_id
+            ), ''),
+            coalesce((select group_concat(value, ' ') from json_each(metas.attributes)), '')
+        from metas
+        where media_id = ?
+        "#,
+    )
+    .bind(media_id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// 検索文字列を空白で区切った語のリストにする
+pub fn split_terms(q: &str) -> Vec<String> {
+    q.split_whitespace().map(|term| term.to_string()).collect()
+}
+
+/// trigram で検索できる最小の文字数
+pub const MIN_MATCH_LENGTH: usize = 3;
+
+/// FTS5 のクエリとして使えるように語をダブルクォートで囲む
+pub fn quote_term(term: &str) -> String {
+    format!("\"{}\"", term.replace('"', "\"\""))
+}
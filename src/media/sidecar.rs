@@ -0,0 +1,291 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Google Takeout のアルバムのメタ情報のファイル名
+const ALBUM_METADATA_FILE_NAME: &str = "metadata.json";
+
+/// Google Takeout が切り詰める `.json` の前のファイル名の長さ
+const TAKEOUT_NAME_LENGTH: usize = 46;
+
+/// Google Takeout の年ごとのディレクトリ、アルバムではない
+static YEAR_DIRECTORY: Lazy<Regex> = Lazy::new(|| Regex::new(r"^Photos from \d{4}$").unwrap());
+
+/// 重複したファイル名に付く `(1)` など
+static DUPLICATE_SUFFIX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(.*)(\(\d+\))$").unwrap());
+
+/// エクスポートした画像に添えられた JSON から読み込んだメタ情報
+/// Google Takeout と、Apple Photos から osxphotos などで書き出したものを読み込める
+#[derive(Debug, Default, Clone)]
+pub struct Sidecar {
+    /// 撮影日時 (UTC)
+    pub date: Option<NaiveDateTime>,
+    /// 緯度と経度
+    pub location: Option<(f64, f64)>,
+    pub description: Option<String>,
+    /// 含まれているアルバム、タグとして付ける
+    pub albums: Vec<String>,
+}
+
+impl Sidecar {
+    /// 画像に対応する JSON を探して読み込む、見つからなければ `None`
+    pub async fn find(origin: &Path) -> Result<Option<Self>> {
+        let path = match sidecar_candidates(origin)
+            .into_iter()
+            .find(|path| path.is_file())
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let value: Value = serde_json::from_slice(&tokio::fs::read(&path).await?)
+            .map_err(|e| anyhow!("invalid sidecar {:?}: {}", path, e))?;
+        // exiftool の形式は 1 件の配列になっている
+        let value = match value {
+            Value::Array(mut values) if !values.is_empty() => values.swap_remove(0),
+            value => value,
+        };
+
+        let mut sidecar = if value.get("photoTakenTime").is_some() {
+            let mut sidecar = Sidecar::from_takeout(&value);
+            sidecar.albums.extend(takeout_album(origin).await);
+            sidecar
+        } else {
+            Sidecar::from_apple(&value)
+        };
+        sidecar.albums = sidecar
+            .albums
+            .iter()
+            // カンマはタグに使えないので置き換える
+            .map(|album| {
+                album
+                    .replace(',', " ")
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|album| !album.is_empty())
+            .collect();
+        sidecar.albums.sort();
+        sidecar.albums.dedup();
+
+        Ok(Some(sidecar))
+    }
+
+    /// Google Takeout の形式
+    /// 位置情報がない場合は `0.0` になっている
+    fn from_takeout(value: &Value) -> Self {
+        let date = value
+            .pointer("/photoTakenTime/timestamp")
+            .and_then(|timestamp| match timestamp {
+                Value::String(timestamp) => timestamp.parse().ok(),
+                timestamp => timestamp.as_i64(),
+            })
+            .and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0));
+
+        let location = ["geoData", "geoDataExif"].iter().find_map(|key| {
+            let latitude = value.get(key)?.get("latitude")?.as_f64()?;
+            let longitude = value.get(key)?.get("longitude")?.as_f64()?;
+            Some((latitude, longitude)).filter(|location| *location != (0.0, 0.0))
+        });
+
+        Sidecar {
+            date,
+            location,
+            description: get_string(value, &["description"]),
+            albums: vec![],
+        }
+    }
+
+    /// Apple Photos から書き出した形式
+    /// osxphotos の JSON と exiftool の形式のキーを読む
+    fn from_apple(value: &Value) -> Self {
+        let date = get_string(value, &["date"])
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+            .map(|date| date.naive_utc())
+            .or_else(|| {
+                let date = get_string(value, &["EXIF:DateTimeOriginal", "XMP:DateCreated"])?;
+                let date = Local.datetime_from_str(&date, "%Y:%m:%d %H:%M:%S").ok()?;
+                Some(date.naive_utc())
+            });
+
+        let location = match (get_f64(value, "latitude"), get_f64(value, "longitude")) {
+            (Some(latitude), Some(longitude)) => Some((latitude, longitude)),
+            _ => {
+                // 南緯と西経は負にする
+                let get = |key: &str, negative: &str| -> Option<f64> {
+                    let degrees = get_f64(value, key)?.abs();
+                    match get_string(value, &[&format!("{}Ref", key)]) {
+                        Some(reference) if reference.starts_with(negative) => Some(-degrees),
+                        _ => Some(degrees),
+                    }
+                };
+                get("EXIF:GPSLatitude", "S").zip(get("EXIF:GPSLongitude", "W"))
+            }
+        };
+
+        let albums = ["albums", "XMP:TagsList"]
+            .iter()
+            .filter_map(|key| value.get(key)?.as_array())
+            .flatten()
+            .filter_map(|album| album.as_str().map(String::from))
+            .collect();
+
+        Sidecar {
+            date,
+            location,
+            description: get_string(value, &["description", "XMP:Description"]),
+            albums,
+        }
+    }
+}
+
+/// 画像に対応する JSON のファイル名の候補
+/// `IMG_1234.jpg.json` の他に、Google Takeout が付ける次のような名前がある
+/// - `IMG_1234.jpg.supplemental-metadata.json`
+/// - 編集したもの: `IMG_1234-edited.jpg` -> `IMG_1234.jpg.json`
+/// - 重複したもの: `IMG_1234(1).jpg` -> `IMG_1234.jpg(1).json`
+/// - 長い名前は `.json` の前が 46 文字に切り詰められる
+fn sidecar_candidates(origin: &Path) -> Vec<PathBuf> {
+    let dir = origin.parent().unwrap_or_else(|| Path::new(""));
+    let file_name = match origin.file_name() {
+        Some(file_name) => file_name.to_string_lossy().to_string(),
+        None => return vec![],
+    };
+    let stem = origin
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = origin
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut names = vec![file_name.clone()];
+    if let Some(stem) = stem.strip_suffix("-edited") {
+        names.push(format!("{}{}", stem, extension));
+    }
+    let mut candidates = vec![];
+    for name in names {
+        candidates.push(format!("{}.json", name));
+        candidates.push(format!("{}.supplemental-metadata.json", name));
+        candidates.push(format!("{}.json", truncate(&name, TAKEOUT_NAME_LENGTH)));
+    }
+    if let Some(captures) = DUPLICATE_SUFFIX.captures(&stem) {
+        candidates.push(format!(
+            "{}{}{}.json",
+            &captures[1], extension, &captures[2]
+        ));
+    }
+    candidates.push(format!("{}.json", stem));
+
+    candidates.into_iter().map(|name| dir.join(name)).collect()
+}
+
+/// Google Takeout のアルバム名
+/// ディレクトリの `metadata.json` のタイトルか、年ごとのものでなければディレクトリ名を使う
+async fn takeout_album(origin: &Path) -> Option<String> {
+    let dir = origin.parent()?;
+    if let Ok(metadata) = tokio::fs::read(dir.join(ALBUM_METADATA_FILE_NAME)).await {
+        let value: Value = serde_json::from_slice(&metadata).ok()?;
+        return get_string(&value, &["title"]);
+    }
+    let name = dir.file_name()?.to_string_lossy().to_string();
+    if YEAR_DIRECTORY.is_match(&name) || name == "Google Photos" {
+        return None;
+    }
+    Some(name)
+}
+
+/// 最初に見つかった空でない文字列を返す
+fn get_string(value: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .filter_map(|key| value.get(key)?.as_str())
+        .map(|value| value.trim())
+        .find(|value| !value.is_empty())
+        .map(String::from)
+}
+
+/// 文字列で入っている場合も読む
+fn get_f64(value: &Value, key: &str) -> Option<f64> {
+    match value.get(key)? {
+        Value::String(value) => value.parse().ok(),
+        value => value.as_f64(),
+    }
+}
+
+/// 文字の境界で `length` 文字以内に切り詰める
+fn truncate(name: &str, length: usize) -> &str {
+    match name.char_indices().nth(length) {
+        Some((index, _)) => &name[..index],
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(origin: &str) -> Vec<String> {
+        sidecar_candidates(Path::new(origin))
+            .into_iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn candidates_of_plain_name() {
+        assert_eq!(
+            candidates("/takeout/IMG_1234.jpg"),
+            vec![
+                "/takeout/IMG_1234.jpg.json",
+                "/takeout/IMG_1234.jpg.supplemental-metadata.json",
+                "/takeout/IMG_1234.jpg.json",
+                "/takeout/IMG_1234.json",
+            ]
+        );
+    }
+
+    #[test]
+    fn candidates_of_edited() {
+        let candidates = candidates("/takeout/IMG_1234-edited.jpg");
+        assert!(candidates.contains(&"/takeout/IMG_1234-edited.jpg.json".to_string()));
+        assert!(candidates.contains(&"/takeout/IMG_1234.jpg.json".to_string()));
+        assert!(
+            candidates.contains(&"/takeout/IMG_1234.jpg.supplemental-metadata.json".to_string())
+        );
+    }
+
+    #[test]
+    fn candidates_of_duplicate() {
+        // 番号は拡張子の後ろに移る
+        let duplicated = candidates("/takeout/IMG_1234(1).jpg");
+        assert!(duplicated.contains(&"/takeout/IMG_1234.jpg(1).json".to_string()));
+        assert!(!duplicated.contains(&"/takeout/IMG_1234(1).jpg(1).json".to_string()));
+
+        let duplicated = candidates("/takeout/IMG_1234(12).jpg");
+        assert!(duplicated.contains(&"/takeout/IMG_1234.jpg(12).json".to_string()));
+    }
+
+    #[test]
+    fn candidates_of_long_name() {
+        // `.json` の前が 46 文字に切り詰められる
+        let name = "PXL_20200101_123456789_VERY_LONG_NAME_FOR_TEST.jpg";
+        let truncated = "PXL_20200101_123456789_VERY_LONG_NAME_FOR_TEST";
+        assert_eq!(truncated.len(), TAKEOUT_NAME_LENGTH);
+        let candidates = candidates(&format!("/takeout/{}", name));
+        assert!(candidates.contains(&format!("/takeout/{}.json", truncated)));
+    }
+
+    #[test]
+    fn truncate_on_char_boundary() {
+        assert_eq!(truncate("abc", 46), "abc");
+        assert_eq!(truncate("abcdef", 3), "abc");
+        // バイト数ではなく文字数で数える
+        assert_eq!(truncate("写真の名前.jpg", 3), "写真の");
+        let name = "あ".repeat(50);
+        assert_eq!(truncate(&name, TAKEOUT_NAME_LENGTH).chars().count(), 46);
+    }
+}
@@ -0,0 +1,140 @@
+use super::{meta::MediaVisibility, search::update_search_index};
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::{prelude::*, query_as, SqliteConnection};
+
+/// メディアに付けるタグ
+#[derive(FromRow, Debug, Clone)]
+pub struct Tag {
+    #[allow(dead_code)]
+    pub tag_id: i64,
+    pub name: String,
+}
+
+/// タグとそのタグが付いているメディアの件数
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct TagCount {
+    pub name: String,
+    pub count: i64,
+}
+
+impl Tag {
+    /// タグ名の前後の空白を取り除いて検証する
+    /// カンマはフィルタの区切り文字に使うので含められない
+    pub fn normalize(name: &str) -> Result<String> {
+        let name = name.trim();
+        ensure!(!name.is_empty(), "tag must not be empty");
+        ensure!(!name.contains(','), "tag must not contain `,`: {}", name);
+        Ok(name.to_string())
+    }
+
+    /// 複数のメディアに複数のタグを付ける
+    /// 既に付いているタグは無視する
+    pub async fn attach(
+        conn: &mut SqliteConnection,
+        media_ids: &[String],
+        names: &[String],
+    ) -> Result<()> {
+        let names = names
+            .iter()
+            .map(|name| Tag::normalize(name))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut tx = Connection::begin(conn).await?;
+        for name in &names {
+            let _ = sqlx::query("insert or ignore into tags (name) values (?)")
+                .bind(name)
+                .execute(&mut tx)
+                .await?;
+            for media_id in media_ids {
+                let _ = sqlx::query(
+                    r#"
+                    insert or ignore into media_tags (media_id, tag_id)
+                    select ?, tag_id from tags where name = ?
+                    "#,
+                )
+                .bind(media_id)
+                .bind(name)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        for media_id in media_ids {
+            update_search_index(&mut tx, media_id).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// 複数のメディアから複数のタグを外す
+    /// どのメディアにも付いていないタグは削除する
+    pub async fn detach(
+        conn: &mut SqliteConnection,
+        media_ids: &[String],
+        names: &[String],
+    ) -> Result<()> {
+        let mut tx = Connection::begin(conn).await?;
+        for name in names {
+            for media_id in media_ids {
+                let _ = sqlx::query(
+                    r#"
+                    delete from media_tags
+                    where media_id = ? and tag_id in (select tag_id from tags where name = ?)
+                    "#,
+                )
+                .bind(media_id)
+                .bind(name.trim())
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        let _ = sqlx::query("delete from tags where tag_id not in (select tag_id from media_tags)")
+            .execute(&mut tx)
+            .await?;
+        for media_id in media_ids {
+            update_search_index(&mut tx, media_id).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// メディアに付いているタグ名を取得する
+    pub async fn list_by_media(conn: &mut SqliteConnection, media_id: &str) -> Result<Vec<String>> {
+        let tags: Vec<Tag> = query_as(
+            r#"
+            select tags.* from tags
+            join media_tags using (tag_id)
+            where media_tags.media_id = ?
+            order by tags.name
+            "#,
+        )
+        .bind(media_id)
+        .fetch_all(conn)
+        .await?;
+        Ok(tags.into_iter().map(|tag| tag.name).collect())
+    }
+}
+
+impl TagCount {
+    /// タグの一覧を件数の多い順に取得する
+    /// ゴミ箱に入っているメディアは数えない
+    pub async fn list(conn: &mut SqliteConnection, include_private: bool) -> Result<Vec<Self>> {
+        let tags = query_as(
+            r#"
+            select tags.name, count(metas.media_id) as count from tags
+            join media_tags using (tag_id)
+            join metas using (media_id)
+            where metas.deleted_at is null and (? or metas.visibility = ?)
+            group by tags.tag_id
+            order by count desc, tags.name
+            "#,
+        )
+        .bind(include_private)
+        .bind(MediaVisibility::Public)
+        .fetch_all(conn)
+        .await?;
+        Ok(tags)
+    }
+}
@@ -1,38 +1,155 @@
+use super::meta::Color;
 use anyhow::Result;
 use std::path::Path;
 
 // リサイズ後の画像サイズ
-pub const TARGET_SIZE: usize = 480;
+pub const TARGET_SIZE: u32 = 480;
 
 // リサイズする際のフィルタ種別 https://docs.rs/image/latest/image/imageops/enum.FilterType.html
 pub const IMAGE_FILTER_TYPE: image::imageops::FilterType = image::imageops::FilterType::Nearest;
 
+// BlurHash を計算する際に縮小する画像サイズ
+pub const BLURHASH_SIZE: u32 = 64;
+
+// BlurHash の横と縦の成分数
+pub const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+// パレットに含める色の最大数
+pub const PALETTE_SIZE: usize = 5;
+
+// 色を数える際に RGB の各チャンネルに残すビット数
+pub const PALETTE_QUANTIZE_BITS: u32 = 3;
+
+// この割合に満たない色はパレットに含めない
+pub const PALETTE_MIN_RATIO: f64 = 0.02;
+
+/// サムネイルの作り方
+/// デフォルトは上の定数で、設定ファイルの `[thumbnail]` で変えられる
+#[derive(Debug, Clone)]
+pub struct ThumbOption {
+    pub size: u32,
+    pub filter: image::imageops::FilterType,
+    pub blurhash_size: u32,
+    pub blurhash_components: (u32, u32),
+    pub palette_size: usize,
+    pub palette_quantize_bits: u32,
+    pub palette_min_ratio: f64,
+}
+
+impl Default for ThumbOption {
+    fn default() -> Self {
+        ThumbOption {
+            size: TARGET_SIZE,
+            filter: IMAGE_FILTER_TYPE,
+            blurhash_size: BLURHASH_SIZE,
+            blurhash_components: BLURHASH_COMPONENTS,
+            palette_size: PALETTE_SIZE,
+            palette_quantize_bits: PALETTE_QUANTIZE_BITS,
+            palette_min_ratio: PALETTE_MIN_RATIO,
+        }
+    }
+}
+
+/// サムネイルを作る際に得られる情報
+#[derive(Debug, Clone)]
+pub struct ThumbInfo {
+    /// オリジナルの画像の幅
+    pub width: u32,
+    /// オリジナルの画像の高さ
+    pub height: u32,
+    /// 読み込み中に表示するプレースホルダ
+    pub blurhash: Option<String>,
+    /// 多く使われている順に並べた色
+    pub palette: Vec<Color>,
+}
+
 /// リサイズして保存する
-pub fn create_thumb(source: &Path, dest: &Path) -> Result<()> {
+pub fn create_thumb(source: &Path, dest: &Path, option: &ThumbOption) -> Result<ThumbInfo> {
     use image::GenericImageView;
     let img = image::open(source)?;
 
     let width = img.width() as usize;
     let height = img.height() as usize;    
+    let target_size = option.size as usize;
 
-    if width > TARGET_SIZE || height > TARGET_SIZE {
+    let thumb = if width > target_size || height > target_size {
         let (target_width, target_height) = 
             if width > height {
-                let ratio: f32 = TARGET_SIZE as f32 / width as f32;
-                (TARGET_SIZE, (height as f32 * ratio) as usize)
+                let ratio: f32 = target_size as f32 / width as f32;
+                (target_size, (height as f32 * ratio) as usize)
             } else {
-                let ratio: f32 = TARGET_SIZE as f32 / height as f32;
-                ((width as f32 * ratio) as usize, TARGET_SIZE)
+                let ratio: f32 = target_size as f32 / height as f32;
+                ((width as f32 * ratio) as usize, target_size)
             };
-        let resized_img = img.resize(
+        img.resize(
             target_width as u32,
             target_height as u32,
-            IMAGE_FILTER_TYPE
-        );
-        let _ = resized_img.save(dest)?;
-        Ok(())
+            option.filter
+        )
     } else {
-        let _ = img.save(dest)?;
-        Ok(())
+        img
+    };
+    thumb.save(dest)?;
+
+    // サムネイルをさらに縮小してから計算する
+    let small = thumb
+        .thumbnail(option.blurhash_size, option.blurhash_size)
+        .to_rgba8();
+    let (components_x, components_y) = option.blurhash_components;
+    let blurhash = blurhash::encode(
+        components_x,
+        components_y,
+        small.width(),
+        small.height(),
+        small.as_raw(),
+    )
+    .ok();
+    let palette = extract_palette(&small, option);
+
+    Ok(ThumbInfo {
+        width: width as u32,
+        height: height as u32,
+        blurhash,
+        palette,
+    })
+}
+
+/// 色を量子化して数え、多い順にパレットを作る
+/// 各色は量子化した範囲に入ったピクセルの平均にする
+fn extract_palette(img: &image::RgbaImage, option: &ThumbOption) -> Vec<Color> {
+    let bits = option.palette_quantize_bits;
+    let shift = 8 - bits;
+    let mut buckets = vec![(0u32, [0u64; 3]); 1 << (bits * 3)];
+    let mut total = 0u32;
+    for pixel in img.pixels() {
+        let [r, g, b, a] = pixel.0;
+        // 透明な部分は数えない
+        if a < 128 {
+            continue;
+        }
+        let index = ((r >> shift) as usize) << (bits * 2)
+            | ((g >> shift) as usize) << bits
+            | (b >> shift) as usize;
+        let (count, sum) = &mut buckets[index];
+        *count += 1;
+        sum[0] += r as u64;
+        sum[1] += g as u64;
+        sum[2] += b as u64;
+        total += 1;
     }
+
+    buckets.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+    buckets
+        .into_iter()
+        .take(option.palette_size)
+        .filter(|(count, _)| *count > 0 && *count as f64 >= total as f64 * option.palette_min_ratio)
+        .map(|(count, sum)| {
+            let count = count as u64;
+            Color::new(
+                (sum[0] / count) as u8,
+                (sum[1] / count) as u8,
+                (sum[2] / count) as u8,
+            )
+        })
+        .collect()
 }
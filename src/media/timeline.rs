@@ -0,0 +1,87 @@
+use super::filter::{IdsFilter, SortOrder};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::*, query_as_with, SqliteConnection};
+
+/// タイムラインを集計する単位
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Year,
+    #[default]
+    Month,
+    Day,
+}
+
+impl Granularity {
+    /// `strftime` に渡すフォーマット
+    fn format(self) -> &'static str {
+        match self {
+            Granularity::Year => "%Y",
+            Granularity::Month => "%Y-%m",
+            Granularity::Day => "%Y-%m-%d",
+        }
+    }
+}
+
+/// タイムラインのひとつの区間
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct TimelineBucket {
+    /// `2022`, `2022-01`, `2022-01-20` の形式で、`/media/ids` の `date` にそのまま使える
+    pub key: String,
+    pub count: i64,
+    /// 区間の中で一番古いメディア
+    pub first: String,
+    /// 区間の中で一番新しいメディア
+    pub last: String,
+}
+
+impl TimelineBucket {
+    /// `filter` で絞り込んだメディアを `granularity` ごとに集計する
+    /// 区間の区切りは `filter` の `tz` に従う、`cursor` と `count` は使わない
+    pub async fn list(
+        conn: &mut SqliteConnection,
+        filter: &IdsFilter,
+        granularity: Granularity,
+        include_private: bool,
+    ) -> Result<Vec<Self>> {
+        let modifier = filter.zone()?.sqlite_modifier();
+        let order = match filter.order.unwrap_or_default() {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        };
+        let (conditions, arguments) = filter.conditions(include_private)?.into_parts();
+
+        // date は UTC で保存しているので、タイムゾーンの日時にしてから区切る
+        let sql = format!(
+            r#"
+            with filtered as (
+                select media_id, date, strftime('{format}', date, '{modifier}') as key
+                from metas
+                where {conditions}
+            )
+            select
+                key,
+                count(*) as count,
+                (
+                    select f.media_id from filtered f where f.key = buckets.key
+                    order by f.date asc, f.media_id asc limit 1
+                ) as first,
+                (
+                    select f.media_id from filtered f where f.key = buckets.key
+                    order by f.date desc, f.media_id desc limit 1
+                ) as last
+            from filtered buckets
+            group by key
+            order by key {order}
+            "#,
+            format = granularity.format(),
+            modifier = modifier,
+            conditions = conditions,
+            order = order,
+        );
+        let buckets = query_as_with(&sql, arguments).fetch_all(conn).await?;
+
+        Ok(buckets)
+    }
+}
@@ -0,0 +1,314 @@
+use super::{common::*, media::*, meta::*};
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+use sqlx::{prelude::*, query_as, SqliteConnection, SqlitePool};
+use std::path::{Path, PathBuf};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+// アップロードされたファイルを置くディレクトリ、ユーザごとに分ける
+pub const UPLOAD_DIRECTORY_NAME: &str = "upload";
+
+// 書き込み中のファイルを置くディレクトリ
+const PARTIAL_DIRECTORY_NAME: &str = ".partial";
+
+/// 一つのファイルの最大サイズ
+pub const MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// 書き込み中のファイル
+/// 一度に受け取る場合は書き込みながらハッシュ値を計算する
+pub struct UploadFile {
+    path: PathBuf,
+    file: File,
+    hasher: Option<Sha512>,
+    size: u64,
+}
+
+impl UploadFile {
+    /// 新しく書き込む
+    pub async fn create(data_dir: &Path) -> Result<Self> {
+        let path = partial_path(data_dir, &uuid::Uuid::new_v4().to_string());
+        let file = File::create(&path).await?;
+        Ok(UploadFile {
+            path,
+            file,
+            hasher: Some(Sha512::new()),
+            size: 0,
+        })
+    }
+
+    /// 途中まで書き込んだファイルの続きから書き込む
+    /// 途中で止まって `offset` より後ろに書き込まれていた分は捨てる
+    async fn resume(path: PathBuf, offset: u64) -> Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+        ensure!(file.metadata().await?.len() >= offset, "partial file is too short");
+        file.set_len(offset).await?;
+        let _ = tokio::io::AsyncSeekExt::seek(&mut file, std::io::SeekFrom::Start(offset)).await?;
+        Ok(UploadFile {
+            path,
+            file,
+            hasher: None,
+            size: offset,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        ensure!(
+            self.size + chunk.len() as u64 <= MAX_UPLOAD_SIZE,
+            "file is too large"
+        );
+        self.file.write_all(chunk).await?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(chunk);
+        }
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// 書き込みを終えて、パスとハッシュ値を返す
+    pub async fn finish(mut self) -> Result<(PathBuf, Option<Vec<u8>>)> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        let hashed = self
+            .hasher
+            .map(|hasher| hasher.finalize().into_iter().collect());
+        Ok((self.path, hashed))
+    }
+
+    pub async fn discard(self) {
+        drop(self.file);
+        let _ = tokio::fs::remove_file(&self.path).await;
+    }
+}
+
+/// 途中から再開できるアップロード
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct Upload {
+    pub upload_id: String,
+    pub user_id: i64,
+    pub file_name: String,
+    pub upload_length: i64,
+    /// 受け取ったバイト数
+    pub upload_offset: i64,
+    /// 取り込み終わったメディア
+    pub media_id: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Upload {
+    pub async fn create(
+        conn: &mut SqliteConnection,
+        data_dir: &Path,
+        user_id: i64,
+        file_name: &str,
+        upload_length: u64,
+    ) -> Result<Self> {
+        ensure!(upload_length <= MAX_UPLOAD_SIZE, "file is too large");
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        let _ = File::create(partial_path(data_dir, &upload_id)).await?;
+
+        let now = Utc::now().naive_utc();
+        let _ = sqlx::query(
+            r#"
+            insert into uploads
+            (upload_id, user_id, file_name, upload_length, upload_offset, created_at, updated_at)
+            values (?, ?, ?, ?, 0, ?, ?)
+            "#,
+        )
+        .bind(&upload_id)
+        .bind(user_id)
+        .bind(file_name)
+        .bind(upload_length as i64)
+        .bind(now)
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+        Upload::get(conn, &upload_id)
+            .await?
+            .ok_or_else(|| anyhow!("upload {} is not saved", upload_id))
+    }
+
+    pub async fn get(conn: &mut SqliteConnection, upload_id: &str) -> Result<Option<Self>> {
+        let upload = query_as("select * from uploads where upload_id = ?")
+            .bind(upload_id)
+            .fetch_optional(conn)
+            .await?;
+        Ok(upload)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.upload_offset == self.upload_length
+    }
+
+    /// 受け取ったところから続きを書き込む
+    pub async fn resume(&self, data_dir: &Path) -> Result<UploadFile> {
+        let path = partial_path(data_dir, &self.upload_id);
+        UploadFile::resume(path, self.upload_offset as u64).await
+    }
+
+    /// 受け取ったバイト数を記録する
+    pub async fn update_offset(&mut self, conn: &mut SqliteConnection, offset: u64) -> Result<()> {
+        ensure!(offset as i64 <= self.upload_length, "exceeds upload length");
+        self.upload_offset = offset as i64;
+        self.updated_at = Utc::now().naive_utc();
+        let _ = sqlx::query(
+            "update uploads set upload_offset = ?, updated_at = ? where upload_id = ?",
+        )
+        .bind(self.upload_offset)
+        .bind(self.updated_at)
+        .bind(&self.upload_id)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_media_id(
+        &mut self,
+        conn: &mut SqliteConnection,
+        media_id: &str,
+    ) -> Result<()> {
+        self.media_id = Some(media_id.to_owned());
+        self.updated_at = Utc::now().naive_utc();
+        let _ = sqlx::query("update uploads set media_id = ?, updated_at = ? where upload_id = ?")
+            .bind(&self.media_id)
+            .bind(self.updated_at)
+            .bind(&self.upload_id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// 途中のファイルと一緒に削除する
+    pub async fn delete(&self, conn: &mut SqliteConnection, data_dir: &Path) -> Result<()> {
+        let _ = tokio::fs::remove_file(partial_path(data_dir, &self.upload_id)).await;
+        let _ = sqlx::query("delete from uploads where upload_id = ?")
+            .bind(&self.upload_id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
+
+impl Media {
+    /// アップロードされたファイルを `directory` に移してから生成する
+    /// 既に取り込んだものと同じファイルであれば移さずに捨て、既存のメディアと `false` を返す
+    pub async fn import_upload(
+        pool: &SqlitePool,
+        data_dir: &Path,
+        directory: &Path,
+        file_name: &str,
+        partial: &Path,
+        hashed: Option<Vec<u8>>,
+        option: &MediaGenerateOption,
+    ) -> Result<(Self, bool)> {
+        let hashed = match hashed {
+            Some(hashed) => hashed,
+            None => get_file_hash(partial).await?,
+        };
+
+        let existing = MediaMeta::get_by_hashed(&mut *pool.acquire().await?, &hashed).await;
+        if let Ok(meta) = existing {
+            let _ = tokio::fs::remove_file(partial).await;
+            return Ok((meta.into(), false));
+        }
+
+        let origin = move_to_unique_path(partial, directory, file_name).await?;
+        Media::import_upload_at(pool, data_dir, &origin, Some(hashed), option).await
+    }
+
+    /// `origin` に置いたアップロードされたファイルから生成する
+    /// 既に取り込んだものと同じファイルでも `origin` に残し、既存のメディアと `false` を返す
+    pub async fn import_upload_at(
+        pool: &SqlitePool,
+        data_dir: &Path,
+        origin: &Path,
+        hashed: Option<Vec<u8>>,
+        option: &MediaGenerateOption,
+    ) -> Result<(Self, bool)> {
+        let hashed = match hashed {
+            Some(hashed) => hashed,
+            None => get_file_hash(origin).await?,
+        };
+
+        let existing = MediaMeta::get_by_hashed(&mut *pool.acquire().await?, &hashed).await;
+        if let Ok(meta) = existing {
+            return Ok((meta.into(), false));
+        }
+
+        match Media::generate_hashed(pool, origin, hashed, data_dir, option).await {
+            Ok(media) => Ok((media, true)),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(origin).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// ユーザがアップロードしたファイルを置くディレクトリ
+pub fn upload_directory(data_dir: &Path, user_name: &str) -> PathBuf {
+    data_dir.join(UPLOAD_DIRECTORY_NAME).join(user_name)
+}
+
+/// ディレクトリを除いたファイル名にする、取り込めないファイルの場合は `None`
+pub fn upload_file_name(file_name: &str) -> Option<String> {
+    let file_name = Path::new(file_name).file_name()?.to_string_lossy().to_string();
+    if file_name.starts_with('.') || !is_target(Path::new(&file_name)) {
+        return None;
+    }
+    Some(file_name)
+}
+
+fn partial_path(data_dir: &Path, name: &str) -> PathBuf {
+    let directory = data_dir.join(UPLOAD_DIRECTORY_NAME).join(PARTIAL_DIRECTORY_NAME);
+    let _ = std::fs::create_dir_all(&directory);
+    directory.join(name)
+}
+
+/// 書き込み終えたファイルを `directory` に移して、移した先の絶対パスを返す
+/// 同じ名前のファイルがあれば `name-1.jpg` のように番号をつける
+/// 同時に同じ名前でアップロードされても上書きしないように、`hard_link` で名前を確保してから元のファイルを消す
+pub async fn move_to_unique_path(
+    partial: &Path,
+    directory: &Path,
+    file_name: &str,
+) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(directory).await?;
+    let directory = tokio::fs::canonicalize(directory).await?;
+
+    let file_name = Path::new(file_name);
+    let stem = file_name
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = file_name
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut path = directory.join(file_name);
+    let mut n = 0;
+    loop {
+        match tokio::fs::hard_link(partial, &path).await {
+            Ok(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                n += 1;
+                path = directory.join(format!("{}-{}.{}", stem, n, extension));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let _ = tokio::fs::remove_file(partial).await;
+    Ok(path)
+}
@@ -0,0 +1,34 @@
+use crate::user::User;
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use anyhow::Result;
+use sqlx::SqlitePool;
+
+/// Basic 認証で `users` のユーザを確かめる
+/// ヘッダがないか一致しない場合は `None`
+pub async fn authenticate(req: &HttpRequest, pool: &SqlitePool) -> Result<Option<User>> {
+    let credentials = match req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| base64::decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok())
+    {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+    let (name, password) = match credentials.split_once(':') {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+
+    let mut conn = pool.acquire().await?;
+    User::authenticate(&mut conn, name, password).await
+}
+
+/// 認証が必要なことを返す
+pub fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"miruku\""))
+        .body("")
+}
@@ -0,0 +1,97 @@
+use crate::server::{
+    auth::{authenticate, unauthorized},
+    AppState,
+};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+pub mod request {
+    use crate::media::JobStatus;
+    use serde::Deserialize;
+
+    /// 取得するジョブの状態と件数
+    #[derive(Deserialize)]
+    pub struct Jobs {
+        pub status: Option<JobStatus>,
+        pub count: Option<u64>,
+    }
+}
+
+pub mod response {
+    use crate::media::Job;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    pub struct Jobs {
+        pub jobs: Vec<Job>,
+    }
+}
+
+// 件数を指定しなかった場合に返すジョブの数
+const DEFAULT_JOBS_COUNT: u64 = 100;
+
+/// 監視している場合に取り込みの状況を返すAPI
+#[get("/admin/watcher")]
+pub async fn get_watcher_status(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    // オリジナルのパスやエラーを含むので、ログインしたユーザだけが見られる
+    // TODO: 管理者だけが見られるようにしたい
+    match authenticate(&req, &state.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    }
+
+    match &state.watcher {
+        Some(status) => {
+            let status = status.lock().unwrap().clone();
+            HttpResponse::Ok().json(status)
+        }
+        None => HttpResponse::NotFound().body(""),
+    }
+}
+
+/// 取り込みジョブを新しく更新された順に返すAPI
+/// `status` で状態を絞り込める
+#[get("/admin/jobs")]
+pub async fn get_jobs(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    use super::media::response::Error;
+    use crate::media::Job;
+
+    // オリジナルのパスやエラーを含むので、ログインしたユーザだけが見られる
+    // TODO: 管理者だけが見られるようにしたい
+    match authenticate(&req, &state.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    }
+
+    let query = match web::Query::<request::Jobs>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::BadRequest().json(Error::new(err));
+        }
+    };
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let count = query.count.unwrap_or(DEFAULT_JOBS_COUNT);
+    match Job::list(&mut conn, query.status, count).await {
+        Ok(jobs) => HttpResponse::Ok().json(response::Jobs { jobs }),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use crate::{
+    media::*,
+    server::{auth::*, AppState},
+    user::User,
+};
+use actix_files::{file_extension_to_mime, NamedFile};
+use actix_web::{http::Method, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::path::{Path, PathBuf};
+use tokio_stream::StreamExt;
+
+// WebDAV のルート
+const DAV_ROOT: &str = "/dav";
+
+// 対応しているメソッド
+const ALLOW: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, MKCOL";
+
+// アップロードを受け付けるコレクション
+const INBOX_NAME: &str = "inbox";
+
+// パスのセグメントでエスケープしない文字
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// WebDAV のリソース
+/// ライブラリは `{年}/{月}/{ファイル名}` の読み取り専用のツリーとして見せる
+/// `inbox/` はユーザのアップロード先で、`PUT` したファイルを取り込む
+enum Resource {
+    Root,
+    Year(String),
+    Month(String, String),
+    Media(Box<MediaMeta>),
+    /// ユーザのアップロード先からの相対パス
+    Inbox(PathBuf),
+}
+
+/// PROPFIND で返すひとつのリソース
+struct Entry {
+    href: String,
+    name: String,
+    collection: bool,
+    length: Option<u64>,
+    modified: Option<DateTime<Utc>>,
+    etag: Option<String>,
+}
+
+/// WebDAV (class 1) で、ライブラリを年と月ごとのツリーとして見せるAPI
+/// `OPTIONS` 以外は `users` のユーザでの Basic 認証が必要
+/// ユーザごとの公開範囲はまだないので、ツリーには公開しているものだけを見せる
+pub async fn dav(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    payload: web::Payload,
+) -> HttpResponse {
+    if req.method() == Method::OPTIONS {
+        return HttpResponse::Ok()
+            .insert_header(("DAV", "1"))
+            .insert_header(("MS-Author-Via", "DAV"))
+            .insert_header(("Allow", ALLOW))
+            .finish();
+    }
+
+    let user = match authenticate(&req, &state.pool).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let segments = match segments(req.match_info().query("path")) {
+        Some(segments) => segments,
+        None => return HttpResponse::BadRequest().body(""),
+    };
+
+    // 作る前のリソースを指定するメソッド
+    match req.method().as_str() {
+        "PUT" => return put(&req, &state, &user, &segments, payload).await,
+        "MKCOL" => return mkcol(&state, &user, &segments),
+        _ => {}
+    }
+
+    let resource = match resolve(&state, &user, &segments).await {
+        Ok(Some(resource)) => resource,
+        Ok(None) => return HttpResponse::NotFound().body(""),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    match req.method().as_str() {
+        "GET" | "HEAD" => get(&req, &state, &user, resource).await,
+        "PROPFIND" => propfind(&req, &state, &user, &segments, resource).await,
+        // 読み取り専用
+        _ => method_not_allowed(),
+    }
+}
+
+/// 対応していないメソッドか、ライブラリへの書き込み
+fn method_not_allowed() -> HttpResponse {
+    HttpResponse::MethodNotAllowed()
+        .insert_header(("Allow", ALLOW))
+        .body("")
+}
+
+/// `/2022/01/a.jpg` を分割する、`..` を含む場合は `None`
+fn segments(path: &str) -> Option<Vec<String>> {
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .map(|segment| segment.to_string())
+        .collect::<Vec<_>>();
+    if segments.iter().any(|segment| segment == "..") {
+        return None;
+    }
+    Some(segments)
+}
+
+async fn resolve(
+    state: &AppState,
+    user: &User,
+    segments: &[String],
+) -> anyhow::Result<Option<Resource>> {
+    let resource = match segments {
+        [] => Some(Resource::Root),
+        [inbox, rest @ ..] if inbox == INBOX_NAME => {
+            let path = rest.iter().collect::<PathBuf>();
+            if upload_directory(&state.data_dir, &user.name)
+                .join(&path)
+                .exists()
+                || path.as_os_str().is_empty()
+            {
+                Some(Resource::Inbox(path))
+            } else {
+                None
+            }
+        }
+        [year] => {
+            let years = list_buckets(state, None).await?;
+            years
+                .iter()
+                .any(|key| key == year)
+                .then(|| Resource::Year(year.clone()))
+        }
+        [year, month] => {
+            let months = list_buckets(state, Some(year)).await?;
+            months
+                .iter()
+                .any(|key| *key == format!("{}-{}", year, month))
+                .then(|| Resource::Month(year.clone(), month.clone()))
+        }
+        [year, month, name] => list_month(state, year, month)
+            .await?
+            .into_iter()
+            .find(|(file_name, _)| file_name == name)
+            .map(|(_, meta)| Resource::Media(Box::new(meta))),
+        _ => None,
+    };
+    Ok(resource)
+}
+
+/// 年か、`year` の月ごとの区間のキー (`2022`, `2022-01`) を返す
+async fn list_buckets(state: &AppState, year: Option<&str>) -> anyhow::Result<Vec<String>> {
+    let filter = IdsFilter {
+        date: year.map(|year| year.to_string()),
+        ..Default::default()
+    };
+    let granularity = match year {
+        Some(_) => Granularity::Month,
+        None => Granularity::Year,
+    };
+    // 非公開のものしかない区間は見せない
+    let include_private = false;
+    let mut conn = state.pool.acquire().await?;
+    let buckets = match TimelineBucket::list(&mut conn, &filter, granularity, include_private).await
+    {
+        Ok(buckets) => buckets,
+        // 年の形式が正しくない
+        Err(_) if year.is_some() => vec![],
+        Err(e) => return Err(e),
+    };
+    Ok(buckets.into_iter().map(|bucket| bucket.key).collect())
+}
+
+/// 月のメディアをファイル名と一緒に古い順に返す
+/// 同じ名前のファイルがある場合は `media_id` の先頭をつけて区別する
+async fn list_month(
+    state: &AppState,
+    year: &str,
+    month: &str,
+) -> anyhow::Result<Vec<(String, MediaMeta)>> {
+    let filter = IdsFilter {
+        date: Some(format!("{}-{}", year, month)),
+        ..Default::default()
+    };
+    let (from, to) = match filter.date_range() {
+        Ok((Some(from), Some(to))) => (from, to),
+        _ => return Ok(vec![]),
+    };
+
+    let mut conn = state.pool.acquire().await?;
+    let mut metas = MediaMeta::list_by_date_range(&mut conn, from, to)
+        .await?
+        .into_iter()
+        .filter(|meta| {
+            meta.visibility == MediaVisibility::Public
+                && meta.deleted_at.is_none()
+                && meta.missing_at.is_none()
+        })
+        .collect::<Vec<_>>();
+    metas.reverse();
+
+    let names = metas
+        .iter()
+        .map(|meta| file_name(&meta.origin))
+        .collect::<Vec<_>>();
+    let files = metas
+        .into_iter()
+        .zip(&names)
+        .map(|(meta, name)| {
+            let duplicated = names.iter().filter(|other| *other == name).count() > 1;
+            let name = if duplicated {
+                let path = Path::new(name);
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let short_id = meta.media_id.chars().take(8).collect::<String>();
+                match path.extension() {
+                    Some(extension) => {
+                        format!("{}-{}.{}", stem, short_id, extension.to_string_lossy())
+                    }
+                    None => format!("{}-{}", stem, short_id),
+                }
+            } else {
+                name.clone()
+            };
+            (name, meta)
+        })
+        .collect();
+    Ok(files)
+}
+
+fn file_name(origin: &str) -> String {
+    Path::new(origin)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+async fn get(req: &HttpRequest, state: &AppState, user: &User, resource: Resource) -> HttpResponse {
+    let path = match resource {
+        Resource::Media(meta) => PathBuf::from(meta.origin),
+        Resource::Inbox(path) => upload_directory(&state.data_dir, &user.name).join(path),
+        _ => return method_not_allowed(),
+    };
+    if !path.is_file() {
+        return method_not_allowed();
+    }
+    match NamedFile::open(&path) {
+        Ok(file) => file.into_response(req),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::NotFound().body("")
+        }
+    }
+}
+
+async fn propfind(
+    req: &HttpRequest,
+    state: &AppState,
+    user: &User,
+    segments: &[String],
+    resource: Resource,
+) -> HttpResponse {
+    // infinity は 1 として扱う
+    let depth = req
+        .headers()
+        .get("Depth")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("1");
+    let href = href(segments);
+
+    let result = match resource {
+        Resource::Media(meta) => Ok(vec![file_entry(
+            href,
+            segments.last().cloned().unwrap_or_default(),
+            Path::new(&meta.origin),
+            Some(format!("\"{}\"", *meta.media_id)),
+        )]),
+        Resource::Inbox(path) => {
+            let path = upload_directory(&state.data_dir, &user.name).join(path);
+            if path.is_file() {
+                let name = segments.last().cloned().unwrap_or_default();
+                Ok(vec![file_entry(href, name, &path, None)])
+            } else {
+                let mut entries = vec![collection_entry(href, segments)];
+                if depth != "0" {
+                    entries.extend(list_directory(segments, &path));
+                }
+                Ok(entries)
+            }
+        }
+        resource => {
+            let mut entries = vec![collection_entry(href, segments)];
+            if depth != "0" {
+                match list_children(state, segments, resource).await {
+                    Ok(children) => entries.extend(children),
+                    Err(e) => {
+                        log::debug!("{:?}", e);
+                        return HttpResponse::InternalServerError().body("");
+                    }
+                }
+            }
+            Ok::<_, anyhow::Error>(entries)
+        }
+    };
+
+    match result {
+        Ok(entries) => HttpResponse::build(actix_web::http::StatusCode::MULTI_STATUS)
+            .content_type("application/xml; charset=utf-8")
+            .body(multistatus(&entries)),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// ルート、年、月のコレクションの中身
+async fn list_children(
+    state: &AppState,
+    segments: &[String],
+    resource: Resource,
+) -> anyhow::Result<Vec<Entry>> {
+    let child = |name: String| {
+        let mut segments = segments.to_vec();
+        segments.push(name);
+        segments
+    };
+
+    let entries = match resource {
+        Resource::Root => {
+            let mut entries = vec![collection_entry(
+                href(&child(INBOX_NAME.to_string())),
+                &child(INBOX_NAME.to_string()),
+            )];
+            for year in list_buckets(state, None).await? {
+                let segments = child(year);
+                entries.push(collection_entry(href(&segments), &segments));
+            }
+            entries
+        }
+        Resource::Year(year) => list_buckets(state, Some(&year))
+            .await?
+            .into_iter()
+            .filter_map(|key| {
+                let month = key.rsplit('-').next()?.to_string();
+                let segments = child(month);
+                Some(collection_entry(href(&segments), &segments))
+            })
+            .collect(),
+        Resource::Month(year, month) => list_month(state, &year, &month)
+            .await?
+            .into_iter()
+            .map(|(name, meta)| {
+                let segments = child(name.clone());
+                let etag = format!("\"{}\"", *meta.media_id);
+                file_entry(href(&segments), name, Path::new(&meta.origin), Some(etag))
+            })
+            .collect(),
+        _ => vec![],
+    };
+    Ok(entries)
+}
+
+/// inbox 以下のディレクトリの中身
+fn list_directory(segments: &[String], path: &Path) -> Vec<Entry> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .flatten()
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mut segments = segments.to_vec();
+            segments.push(name.clone());
+            if entry.path().is_dir() {
+                collection_entry(href(&segments), &segments)
+            } else {
+                file_entry(href(&segments), name, &entry.path(), None)
+            }
+        })
+        .collect()
+}
+
+fn collection_entry(href: String, segments: &[String]) -> Entry {
+    Entry {
+        href: format!("{}/", href.trim_end_matches('/')),
+        name: segments.last().cloned().unwrap_or_default(),
+        collection: true,
+        length: None,
+        modified: None,
+        etag: None,
+    }
+}
+
+fn file_entry(href: String, name: String, path: &Path, etag: Option<String>) -> Entry {
+    let metadata = std::fs::metadata(path).ok();
+    let modified = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.modified().ok())
+        .map(DateTime::<Utc>::from);
+    let etag = etag.or_else(|| {
+        let metadata = metadata.as_ref()?;
+        Some(format!("\"{}-{}\"", metadata.len(), modified?.timestamp()))
+    });
+    Entry {
+        href,
+        name,
+        collection: false,
+        length: metadata.map(|metadata| metadata.len()),
+        modified,
+        etag,
+    }
+}
+
+fn href(segments: &[String]) -> String {
+    let mut href = DAV_ROOT.to_string();
+    for segment in segments {
+        href.push('/');
+        href.push_str(&utf8_percent_encode(segment, SEGMENT).to_string());
+    }
+    if segments.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+fn multistatus(entries: &[Entry]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    for entry in entries {
+        xml.push_str("<D:response>\n");
+        xml.push_str(&format!("<D:href>{}</D:href>\n", escape(&entry.href)));
+        xml.push_str("<D:propstat>\n<D:prop>\n");
+        xml.push_str(&format!(
+            "<D:displayname>{}</D:displayname>\n",
+            escape(&entry.name)
+        ));
+        if entry.collection {
+            xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+        } else {
+            xml.push_str("<D:resourcetype/>\n");
+            // 動画や HEIC もあるので拡張子から決める
+            let extension = Path::new(&entry.name)
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            xml.push_str(&format!(
+                "<D:getcontenttype>{}</D:getcontenttype>\n",
+                file_extension_to_mime(&extension)
+            ));
+        }
+        if let Some(length) = entry.length {
+            xml.push_str(&format!(
+                "<D:getcontentlength>{}</D:getcontentlength>\n",
+                length
+            ));
+        }
+        if let Some(modified) = entry.modified {
+            xml.push_str(&format!(
+                "<D:getlastmodified>{}</D:getlastmodified>\n",
+                modified.format("%a, %d %b %Y %H:%M:%S GMT")
+            ));
+        }
+        if let Some(etag) = &entry.etag {
+            xml.push_str(&format!("<D:getetag>{}</D:getetag>\n", escape(etag)));
+        }
+        xml.push_str("</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n");
+        xml.push_str("</D:response>\n");
+    }
+    xml.push_str("</D:multistatus>\n");
+    xml
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// inbox にディレクトリを作る
+fn mkcol(state: &AppState, user: &User, segments: &[String]) -> HttpResponse {
+    let inbox = upload_directory(&state.data_dir, &user.name);
+    let path = match segments {
+        [name, rest @ ..] if name == INBOX_NAME && !rest.is_empty() => {
+            inbox.join(rest.iter().collect::<PathBuf>())
+        }
+        _ => return method_not_allowed(),
+    };
+    if let Err(err) = std::fs::create_dir_all(&inbox) {
+        log::debug!("{:?}", err);
+        return HttpResponse::InternalServerError().body("");
+    }
+    if path.exists() {
+        return method_not_allowed();
+    }
+    if !path.parent().map(|parent| parent.is_dir()).unwrap_or(false) {
+        return HttpResponse::Conflict().body("");
+    }
+    match std::fs::create_dir(&path) {
+        Ok(_) => HttpResponse::Created().finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// inbox に置いたファイルを取り込む
+/// 新しく取り込んだ場合は `201`、既に同じファイルがある場合は `204` で、どちらも `Media-Id` を返す
+/// 同じファイルでも inbox には残すので、`PUT` したパスでそのまま読める
+/// 同じ名前のファイルがある場合は番号をつけて置き、`Location` で置いた場所を返す
+async fn put(
+    req: &HttpRequest,
+    state: &AppState,
+    user: &User,
+    segments: &[String],
+    mut payload: web::Payload,
+) -> HttpResponse {
+    use super::media::response::Error;
+
+    let (directory, file_name) = match segments {
+        [inbox, rest @ .., file_name] if inbox == INBOX_NAME => (
+            upload_directory(&state.data_dir, &user.name).join(rest.iter().collect::<PathBuf>()),
+            file_name,
+        ),
+        _ => return method_not_allowed(),
+    };
+    let file_name = match upload_file_name(file_name) {
+        Some(file_name) => file_name,
+        None => {
+            let error = Error::new(format!("unsupported file: {}", file_name));
+            return HttpResponse::UnsupportedMediaType().json(error);
+        }
+    };
+
+    let mut file = match UploadFile::create(&state.data_dir).await {
+        Ok(file) => file,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                file.discard().await;
+                return HttpResponse::BadRequest().json(Error::new(err));
+            }
+        };
+        if let Err(err) = file.write(&chunk).await {
+            file.discard().await;
+            return HttpResponse::PayloadTooLarge().json(Error::new(err));
+        }
+    }
+    let (partial, hashed) = match file.finish().await {
+        Ok(finished) => finished,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let origin = match move_to_unique_path(&partial, &directory, &file_name).await {
+        Ok(origin) => origin,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            let _ = tokio::fs::remove_file(&partial).await;
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+    let imported =
+        Media::import_upload_at(&state.pool, &state.data_dir, &origin, hashed, &state.option).await;
+    let (media, created) = match imported {
+        Ok(imported) => imported,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::UnprocessableEntity().json(Error::new(err));
+        }
+    };
+    log::info!(
+        "uploaded {} via dav by {}: {}",
+        req.path(),
+        user.name,
+        *media.meta.media_id
+    );
+
+    let mut response = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::NoContent()
+    };
+    response.insert_header(("Media-Id", media.meta.media_id.as_str()));
+    let placed = origin
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if placed != file_name {
+        let mut segments = segments.to_vec();
+        if let Some(last) = segments.last_mut() {
+            *last = placed;
+        }
+        response.insert_header(("Location", href(&segments)));
+    }
+    response.finish()
+}
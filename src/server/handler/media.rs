@@ -1,8 +1,69 @@
-use crate::server::AppState;
-use actix_web::{get, web, HttpRequest, HttpResponse};
+use crate::server::{
+    auth::{authenticate, unauthorized},
+    AppState,
+};
+use actix_web::{delete, get, patch, post, web, HttpRequest, HttpResponse};
+
+pub mod request {
+    use crate::media::{ColorLabel, Granularity, MediaVisibility};
+    use serde::{Deserialize, Deserializer};
+
+    /// `expand=meta` でメタ情報を埋め込む、その他のパラメータは `IdsFilter` として受け取る
+    #[derive(Deserialize)]
+    pub struct Expand {
+        pub expand: Option<String>,
+    }
+
+    impl Expand {
+        pub fn meta(&self) -> bool {
+            self.expand
+                .iter()
+                .flat_map(|expand| expand.split(','))
+                .any(|expand| expand.trim() == "meta")
+        }
+    }
+
+    /// まとめてメタ情報を取得する
+    #[derive(Deserialize)]
+    pub struct MetaBatch {
+        pub ids: Vec<String>,
+    }
+
+    /// タイムラインの集計方法、その他のパラメータは `IdsFilter` として受け取る
+    #[derive(Deserialize)]
+    pub struct Timeline {
+        pub granularity: Option<Granularity>,
+    }
+
+    /// メタ情報の更新、指定したフィールドのみ更新する
+    #[derive(Deserialize)]
+    pub struct UpdateMeta {
+        pub visibility: Option<MediaVisibility>,
+        pub rating: Option<u8>,
+        pub favorite: Option<bool>,
+        /// `null` を指定するとラベルを外す
+        #[serde(default, deserialize_with = "deserialize_some")]
+        pub label: Option<Option<ColorLabel>>,
+        #[serde(default, deserialize_with = "deserialize_some")]
+        pub title: Option<Option<String>>,
+        #[serde(default, deserialize_with = "deserialize_some")]
+        pub description: Option<Option<String>>,
+    }
+
+    /// 存在しないフィールドと `null` を区別する
+    fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Deserialize::deserialize(deserializer).map(Some)
+    }
+}
 
 pub mod response {
-    use crate::media::MediaId;
+    use crate::media::{
+        Color, ColorLabel, Granularity, MediaId, MediaMetaWithTags, TimelineBucket,
+    };
     use serde::Serialize;
     use std::collections::HashMap;
 
@@ -12,12 +73,91 @@ pub mod response {
         pub origin_name: String,
         pub date: String,
         pub attributes: Option<HashMap<String, String>>,
+        pub deleted_at: Option<String>,
+        /// オリジナルのファイルが見つからなくなった日時
+        pub missing_at: Option<String>,
+        pub tags: Vec<String>,
+        pub rating: u8,
+        pub favorite: bool,
+        pub label: Option<ColorLabel>,
+        pub title: Option<String>,
+        pub description: Option<String>,
+        pub width: Option<u32>,
+        pub height: Option<u32>,
+        pub aspect_ratio: Option<f64>,
+        pub blurhash: Option<String>,
+        pub dominant_color: Option<Color>,
+        pub palette: Vec<Color>,
+        pub device: Option<String>,
+        pub latitude: Option<f64>,
+        pub longitude: Option<f64>,
+        pub thumb_url: String,
+        pub origin_url: String,
+    }
+
+    impl From<MediaMetaWithTags> for Meta {
+        fn from(MediaMetaWithTags { meta, tags }: MediaMetaWithTags) -> Self {
+            Meta {
+                thumb_url: format!("/media/thumb/{}", *meta.media_id),
+                origin_url: format!("/media/origin/{}", *meta.media_id),
+                id: meta.media_id.to_string(),
+                origin_name: meta.origin,
+                date: meta.date.to_string(),
+                attributes: meta.attributes.map(|json| json.0),
+                deleted_at: meta.deleted_at.map(|date| date.to_string()),
+                missing_at: meta.missing_at.map(|date| date.to_string()),
+                tags,
+                rating: meta.rating,
+                favorite: meta.favorite,
+                label: meta.label,
+                title: meta.title,
+                description: meta.description,
+                width: meta.width,
+                height: meta.height,
+                aspect_ratio: meta.aspect_ratio,
+                blurhash: meta.blurhash,
+                dominant_color: meta.dominant_color,
+                palette: meta.palette.map(|json| json.0).unwrap_or_default(),
+                device: meta.device,
+                latitude: meta.latitude,
+                longitude: meta.longitude,
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    pub struct Metas {
+        pub metas: Vec<Meta>,
+    }
+
+    /// リクエストが不正な場合に返す
+    #[derive(Serialize)]
+    pub struct Error {
+        pub error: String,
+    }
+
+    impl Error {
+        pub fn new(error: impl ToString) -> Self {
+            Error {
+                error: error.to_string(),
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    pub struct Timeline {
+        pub granularity: Granularity,
+        pub buckets: Vec<TimelineBucket>,
     }
 
     #[derive(Serialize)]
     pub struct MediaIds {
         pub ids: Vec<MediaId>,
-        pub last: u64, // 一番最後の要素の日付
+        /// `expand=meta` を指定した場合のみ、`ids` と同じ順で入る
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub metas: Option<Vec<Meta>>,
+        pub cursor: Option<String>, // 続きを取得する際に `cursor` に指定する
+        pub has_more: bool,
     }
 }
 
@@ -25,7 +165,6 @@ pub mod response {
 #[get("/media/ids")]
 pub async fn get_media_ids(req: HttpRequest) -> HttpResponse {
     use crate::media::*;
-    use response::MediaIds;
 
     let state = match req.app_data::<web::Data<AppState>>() {
         Some(state) => state,
@@ -38,11 +177,88 @@ pub async fn get_media_ids(req: HttpRequest) -> HttpResponse {
         Ok(filter) => filter,
         Err(err) => {
             log::debug!("{:?}", err);
-            return HttpResponse::BadRequest().body("");
+            return HttpResponse::BadRequest().json(response::Error::new(err));
+        }
+    };
+
+    let expand = match web::Query::<request::Expand>::from_query(req.query_string()) {
+        Ok(expand) => expand.meta(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::BadRequest().json(response::Error::new(err));
+        }
+    };
+
+    filter_media_ids(state, filter.into_inner(), expand).await
+}
+
+/// メディアを全文検索するAPI
+/// `/media/ids` と同じフィルタとページングが使える
+#[get("/media/search")]
+pub async fn search_media(req: HttpRequest) -> HttpResponse {
+    use crate::media::*;
+
+    let state = match req.app_data::<web::Data<AppState>>() {
+        Some(state) => state,
+        _ => {
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let filter = match web::Query::<IdsFilter>::from_query(req.query_string()) {
+        Ok(filter) => filter,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::BadRequest().json(response::Error::new(err));
+        }
+    };
+
+    // 検索文字列は必須にする
+    if filter.q.as_deref().map(str::trim).unwrap_or_default().is_empty() {
+        return HttpResponse::BadRequest().json(response::Error::new("`q` is required"));
+    }
+
+    let expand = match web::Query::<request::Expand>::from_query(req.query_string()) {
+        Ok(expand) => expand.meta(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::BadRequest().json(response::Error::new(err));
         }
     };
 
-    let mut conn = match create_connection(&state.data_dir).await {
+    filter_media_ids(state, filter.into_inner(), expand).await
+}
+
+/// 年、月、日ごとのメディアの件数を取得するAPI
+/// `/media/ids` と同じフィルタが使える
+#[get("/media/timeline")]
+pub async fn get_media_timeline(req: HttpRequest) -> HttpResponse {
+    use crate::media::*;
+
+    let state = match req.app_data::<web::Data<AppState>>() {
+        Some(state) => state,
+        _ => {
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let (filter, option) = match (
+        web::Query::<IdsFilter>::from_query(req.query_string()),
+        web::Query::<request::Timeline>::from_query(req.query_string()),
+    ) {
+        (Ok(filter), Ok(option)) => (filter.into_inner(), option.into_inner()),
+        (Err(err), _) | (_, Err(err)) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::BadRequest().json(response::Error::new(err));
+        }
+    };
+
+    if let Err(err) = filter.validate() {
+        log::debug!("{:?}", err);
+        return HttpResponse::BadRequest().json(response::Error::new(err));
+    }
+
+    let mut conn = match state.pool.acquire().await {
         Ok(conn) => conn,
         Err(err) => {
             log::debug!("{:?}", err);
@@ -50,14 +266,68 @@ pub async fn get_media_ids(req: HttpRequest) -> HttpResponse {
         }
     };
 
-    // TODO: ログインしていないと非公開のものを検索結果に出さないとかしたいね
+    // TODO: ログインしていないと非公開のものを数えないようにしたい
     let include_private = true;
-    match MediaId::filter(&mut conn, filter.into_inner(), include_private).await {
-        Ok((ids, last)) => {
-            let last = last.timestamp_millis() as u64;
-            let response = MediaIds { ids, last };
-            HttpResponse::Ok().json(response)
+    let granularity = option.granularity.unwrap_or_default();
+    match TimelineBucket::list(&mut conn, &filter, granularity, include_private).await {
+        Ok(buckets) => HttpResponse::Ok().json(response::Timeline {
+            granularity,
+            buckets,
+        }),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// フィルタで検索して `MediaIds` を返す
+/// `expand` が `true` の場合はメタ情報も一度に取得して埋め込む
+async fn filter_media_ids(
+    state: &AppState,
+    filter: crate::media::IdsFilter,
+    expand: bool,
+) -> HttpResponse {
+    use crate::media::*;
+    use response::{MediaIds, Meta};
+
+    if let Err(err) = filter.validate() {
+        log::debug!("{:?}", err);
+        return HttpResponse::BadRequest().json(response::Error::new(err));
+    }
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
         }
+    };
+
+    // TODO: ログインしていないと非公開のものを検索結果に出さないとかしたいね
+    let include_private = true;
+    let response = if expand {
+        MediaMetaWithTags::filter(&mut conn, filter, include_private)
+            .await
+            .map(|FilteredMetas { metas, cursor, has_more }| MediaIds {
+                ids: metas.iter().map(|meta| meta.meta.media_id.clone()).collect(),
+                metas: Some(metas.into_iter().map(Meta::from).collect()),
+                cursor: cursor.map(|cursor| cursor.encode()),
+                has_more,
+            })
+    } else {
+        MediaId::filter(&mut conn, filter, include_private)
+            .await
+            .map(|FilteredIds { ids, cursor, has_more }| MediaIds {
+                ids,
+                metas: None,
+                cursor: cursor.map(|cursor| cursor.encode()),
+                has_more,
+            })
+    };
+
+    match response {
+        Ok(response) => HttpResponse::Ok().json(response),
         Err(err) => {
             log::debug!("{:?}", err);
             HttpResponse::InternalServerError().body("")
@@ -70,7 +340,7 @@ pub async fn get_media_ids(req: HttpRequest) -> HttpResponse {
 pub async fn get_media_thumb(path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
     use crate::media::*;
 
-    let mut conn = match create_connection(&state.data_dir).await {
+    let mut conn = match state.pool.acquire().await {
         Ok(conn) => conn,
         Err(err) => {
             log::debug!("{:?}", err);
@@ -105,7 +375,7 @@ pub async fn get_media_thumb(path: web::Path<String>, state: web::Data<AppState>
 pub async fn get_media_origin(path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
     use crate::media::*;
 
-    let mut conn = match create_connection(&state.data_dir).await {
+    let mut conn = match state.pool.acquire().await {
         Ok(conn) => conn,
         Err(err) => {
             log::debug!("{:?}", err);
@@ -134,9 +404,8 @@ pub async fn get_media_origin(path: web::Path<String>, state: web::Data<AppState
 pub async fn get_media_meta(path: web::Path<String>, state: web::Data<AppState>) -> HttpResponse {
     use crate::media::*;
     use response::Meta;
-    use std::ops::Deref;
 
-    let mut conn = match create_connection(&state.data_dir).await {
+    let mut conn = match state.pool.acquire().await {
         Ok(conn) => conn,
         Err(err) => {
             log::debug!("{:?}", err);
@@ -144,17 +413,213 @@ pub async fn get_media_meta(path: web::Path<String>, state: web::Data<AppState>)
         }
     };
 
-    let meta = match MediaMeta::open(&mut conn, &path.into_inner()).await {
+    let meta = match MediaMetaWithTags::list_by_ids(&mut conn, &[path.into_inner()]).await {
+        Ok(metas) => match metas.into_iter().next() {
+            Some(meta) => meta,
+            None => return HttpResponse::NotFound().body(""),
+        },
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    HttpResponse::Ok().json(Meta::from(meta))
+}
+
+/// 複数のメディアのメタ情報をまとめて取得する
+/// 存在しない `media_id` は結果に含めない
+#[post("/media/meta/batch")]
+pub async fn get_media_meta_batch(
+    body: web::Json<request::MetaBatch>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    use crate::media::*;
+    use response::{Meta, Metas};
+
+    // 一度に取得できる件数
+    const MAX_BATCH_SIZE: usize = 500;
+
+    if body.ids.len() > MAX_BATCH_SIZE {
+        return HttpResponse::BadRequest().json(response::Error::new(format!(
+            "ids must be at most {}",
+            MAX_BATCH_SIZE
+        )));
+    }
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    match MediaMetaWithTags::list_by_ids(&mut conn, &body.ids).await {
+        Ok(metas) => HttpResponse::Ok().json(Metas {
+            metas: metas.into_iter().map(Meta::from).collect(),
+        }),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// メタ情報を更新する
+#[patch("/media/meta/{media_id}")]
+pub async fn update_media_meta(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<request::UpdateMeta>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    use crate::media::*;
+    use response::Error;
+    use sqlx::Connection;
+
+    match authenticate(&req, &state.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    }
+
+    if let Some(rating) = body.rating.filter(|rating| *rating > MAX_RATING) {
+        let error = Error::new(format!("rating must be 0 to {}: {}", MAX_RATING, rating));
+        return HttpResponse::BadRequest().json(error);
+    }
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let mut meta = match MediaMeta::open(&mut conn, &path.into_inner()).await {
         Ok(meta) => meta,
         Err(_) => return HttpResponse::NotFound().body(""),
     };
 
-    let response = Meta {
-        id: meta.media_id.deref().clone(),
-        origin_name: meta.origin,
-        date: meta.date.to_string(),
-        attributes: meta.attributes.map(|json| json.0),
+    // 一部のフィールドだけが更新されないように、まとめて一つのトランザクションで更新する
+    let body = body.into_inner();
+    let result = async {
+        let mut tx = Connection::begin(&mut *conn).await?;
+        if let Some(visibility) = body.visibility {
+            meta.update_visibility(&mut tx, visibility).await?;
+        }
+        if let Some(rating) = body.rating {
+            meta.update_rating(&mut tx, rating).await?;
+        }
+        if let Some(favorite) = body.favorite {
+            meta.update_favorite(&mut tx, favorite).await?;
+        }
+        if let Some(label) = body.label {
+            meta.update_label(&mut tx, label).await?;
+        }
+        if let Some(title) = body.title {
+            meta.update_title(&mut tx, title).await?;
+        }
+        if let Some(description) = body.description {
+            meta.update_description(&mut tx, description).await?;
+        }
+        tx.commit().await?;
+        anyhow::Ok(())
     };
 
-    HttpResponse::Ok().json(response)
+    match result.await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// メディアをゴミ箱に入れる
+#[delete("/media/{media_id}")]
+pub async fn delete_media(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    use crate::media::*;
+
+    match authenticate(&req, &state.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    }
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let mut meta = match MediaMeta::open(&mut conn, &path.into_inner()).await {
+        Ok(meta) => meta,
+        Err(_) => return HttpResponse::NotFound().body(""),
+    };
+
+    if meta.is_trashed() {
+        return HttpResponse::NoContent().finish();
+    }
+
+    match meta.trash(&mut conn).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// ゴミ箱に入れたメディアを元に戻す
+#[post("/media/restore/{media_id}")]
+pub async fn restore_media(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    use crate::media::*;
+
+    match authenticate(&req, &state.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    }
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let mut meta = match MediaMeta::open(&mut conn, &path.into_inner()).await {
+        Ok(meta) => meta,
+        Err(_) => return HttpResponse::NotFound().body(""),
+    };
+
+    match meta.restore(&mut conn).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
 }
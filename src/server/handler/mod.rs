@@ -1,3 +1,11 @@
+mod admin;
+mod dav;
 mod media;
+mod tag;
+mod upload;
 
+pub use admin::*;
+pub use dav::*;
 pub use media::*;
+pub use tag::*;
+pub use upload::*;
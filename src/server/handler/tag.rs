@@ -0,0 +1,119 @@
+use crate::server::{
+    auth::{authenticate, unauthorized},
+    AppState,
+};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+
+pub mod request {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct MediaTags {
+        pub ids: Vec<String>,
+        pub tags: Vec<String>,
+    }
+}
+
+/// タグの一覧を件数付きで取得するAPI
+#[get("/tags")]
+pub async fn get_tags(state: web::Data<AppState>) -> HttpResponse {
+    use crate::media::*;
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    // TODO: ログインしていないと非公開のものを数えないようにしたい
+    let include_private = true;
+    match TagCount::list(&mut conn, include_private).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// 複数のメディアにタグを付けるAPI
+#[post("/media/tags")]
+pub async fn add_media_tags(
+    req: HttpRequest,
+    body: web::Json<request::MediaTags>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    use crate::media::*;
+
+    match authenticate(&req, &state.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    }
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    if body.tags.iter().any(|tag| Tag::normalize(tag).is_err()) {
+        return HttpResponse::BadRequest().body("");
+    }
+
+    for media_id in &body.ids {
+        if MediaMeta::open(&mut conn, media_id).await.is_err() {
+            return HttpResponse::NotFound().body("");
+        }
+    }
+
+    match Tag::attach(&mut conn, &body.ids, &body.tags).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// 複数のメディアからタグを外すAPI
+#[delete("/media/tags")]
+pub async fn remove_media_tags(
+    req: HttpRequest,
+    body: web::Json<request::MediaTags>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    use crate::media::*;
+
+    match authenticate(&req, &state.pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    }
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    match Tag::detach(&mut conn, &body.ids, &body.tags).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
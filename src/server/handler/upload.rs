@@ -0,0 +1,486 @@
+use crate::{
+    media::*,
+    server::{auth::*, AppState},
+    user::User,
+};
+use actix_web::{delete, head, options, patch, post, web, HttpRequest, HttpResponse};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+// 対応している tus のバージョン
+const TUS_VERSION: &str = "1.0.0";
+
+pub mod response {
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    pub struct Uploaded {
+        pub file_name: String,
+        pub media_id: String,
+        /// 新しく取り込んだかどうか、既に同じファイルがあれば `false`
+        pub created: bool,
+    }
+
+    #[derive(Serialize)]
+    pub struct Uploads {
+        pub uploads: Vec<Uploaded>,
+    }
+}
+
+use super::media::response::Error;
+
+/// multipart で送られたファイルを取り込むAPI
+/// 書き込みながらハッシュ値を計算し、既にあるファイルであれば既存の `media_id` を返す
+#[post("/media/upload")]
+pub async fn upload_media(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let user = match authenticate(&req, &state.pool).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return unauthorized(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let boundary = match req
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map(multer::parse_boundary)
+    {
+        Some(Ok(boundary)) => boundary,
+        _ => return HttpResponse::BadRequest().json(Error::new("multipart/form-data required")),
+    };
+
+    // multer は Send なストリームしか受け取らないので、チャネルを挟んで同じタスクの中で流し込む
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let feed = async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()));
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    };
+    let multipart = multer::Multipart::new(ReceiverStream::new(rx), boundary);
+    let (_, response) = tokio::join!(feed, receive_files(multipart, &state, &user));
+    response
+}
+
+async fn receive_files(
+    mut multipart: multer::Multipart<'_>,
+    state: &AppState,
+    user: &User,
+) -> HttpResponse {
+    let mut uploads = vec![];
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return HttpResponse::BadRequest().json(Error::new(err)),
+        };
+        // ファイル以外のフィールドは無視する
+        let file_name = match field.file_name() {
+            Some(file_name) => file_name.to_owned(),
+            None => continue,
+        };
+        let file_name = match upload_file_name(&file_name) {
+            Some(file_name) => file_name,
+            None => {
+                let error = Error::new(format!("unsupported file: {}", file_name));
+                return HttpResponse::UnsupportedMediaType().json(error);
+            }
+        };
+
+        let mut file = match UploadFile::create(&state.data_dir).await {
+            Ok(file) => file,
+            Err(err) => {
+                log::debug!("{:?}", err);
+                return HttpResponse::InternalServerError().body("");
+            }
+        };
+        loop {
+            let chunk = match field.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(err) => {
+                    file.discard().await;
+                    return HttpResponse::BadRequest().json(Error::new(err));
+                }
+            };
+            if let Err(err) = file.write(&chunk).await {
+                file.discard().await;
+                return HttpResponse::PayloadTooLarge().json(Error::new(err));
+            }
+        }
+
+        let (partial, hashed) = match file.finish().await {
+            Ok(finished) => finished,
+            Err(err) => {
+                log::debug!("{:?}", err);
+                return HttpResponse::InternalServerError().body("");
+            }
+        };
+        let (media, created) = match Media::import_upload(
+            &state.pool,
+            &state.data_dir,
+            &upload_directory(&state.data_dir, &user.name),
+            &file_name,
+            &partial,
+            hashed,
+            &state.option,
+        )
+        .await
+        {
+            Ok(imported) => imported,
+            Err(err) => {
+                log::debug!("{:?}", err);
+                let _ = tokio::fs::remove_file(&partial).await;
+                return HttpResponse::UnprocessableEntity().json(Error::new(err));
+            }
+        };
+        log::info!("uploaded {} by {}: {}", file_name, user.name, *media.meta.media_id);
+
+        uploads.push(response::Uploaded {
+            file_name,
+            media_id: media.meta.media_id.to_string(),
+            created,
+        });
+    }
+
+    if uploads.is_empty() {
+        return HttpResponse::BadRequest().json(Error::new("no file"));
+    }
+    if uploads.iter().any(|upload| upload.created) {
+        HttpResponse::Created().json(response::Uploads { uploads })
+    } else {
+        HttpResponse::Ok().json(response::Uploads { uploads })
+    }
+}
+
+/// tus で対応している機能を返す
+#[options("/media/upload/resumable")]
+pub async fn resumable_upload_options() -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", "creation,termination"))
+        .insert_header(("Tus-Max-Size", MAX_UPLOAD_SIZE.to_string()))
+        .finish()
+}
+
+/// tus の creation でアップロードを始める
+/// `Upload-Length` と `Upload-Metadata` の `filename` が必要
+#[post("/media/upload/resumable")]
+pub async fn create_resumable_upload(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let user = match authenticate_tus(&req, &state).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let length = match header(&req, "Upload-Length").and_then(|value| value.parse::<u64>().ok()) {
+        Some(length) => length,
+        None => return HttpResponse::BadRequest().json(Error::new("Upload-Length required")),
+    };
+    if length > MAX_UPLOAD_SIZE {
+        return HttpResponse::PayloadTooLarge().json(Error::new("file is too large"));
+    }
+    let file_name = match header(&req, "Upload-Metadata")
+        .and_then(|metadata| metadata_value(metadata, &["filename", "name"]))
+    {
+        Some(file_name) => file_name,
+        None => return HttpResponse::BadRequest().json(Error::new("filename required")),
+    };
+    let file_name = match upload_file_name(&file_name) {
+        Some(file_name) => file_name,
+        None => {
+            let error = Error::new(format!("unsupported file: {}", file_name));
+            return HttpResponse::UnsupportedMediaType().json(error);
+        }
+    };
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+    match Upload::create(&mut conn, &state.data_dir, user.user_id, &file_name, length).await {
+        Ok(upload) => HttpResponse::Created()
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .insert_header((
+                "Location",
+                format!("/media/upload/resumable/{}", upload.upload_id),
+            ))
+            .finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// どこまで受け取ったかを返す
+#[head("/media/upload/resumable/{upload_id}")]
+pub async fn get_resumable_upload(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let upload = match open_upload(&req, &path, &state).await {
+        Ok((_, upload)) => upload,
+        Err(response) => return response,
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Cache-Control", "no-store"))
+        .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+        .insert_header(("Upload-Length", upload.upload_length.to_string()));
+    if let Some(media_id) = &upload.media_id {
+        response.insert_header(("Media-Id", media_id.as_str()));
+    }
+    response.finish()
+}
+
+/// `Upload-Offset` から続きを受け取る
+/// 全て受け取ったら取り込み、`Media-Id` ヘッダで `media_id` を返す
+#[patch("/media/upload/resumable/{upload_id}")]
+pub async fn append_resumable_upload(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    // 他のリクエストが書き込んでいる間は受け付けない
+    let _uploading = match Uploading::start(&state, &path) {
+        Some(uploading) => uploading,
+        None => return HttpResponse::Conflict().body(""),
+    };
+    let (user, mut upload) = match open_upload(&req, &path, &state).await {
+        Ok(opened) => opened,
+        Err(response) => return response,
+    };
+
+    if header(&req, "Content-Type") != Some("application/offset+octet-stream") {
+        return HttpResponse::UnsupportedMediaType().body("");
+    }
+    let offset = header(&req, "Upload-Offset").and_then(|value| value.parse::<i64>().ok());
+    if offset != Some(upload.upload_offset) || upload.media_id.is_some() {
+        return HttpResponse::Conflict().body("");
+    }
+
+    let mut file = match upload.resume(&state.data_dir).await {
+        Ok(file) => file,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    // 接続が切れても受け取ったところまでは記録して、続きから再開できるようにする
+    let mut failed = None;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                failed = Some(HttpResponse::BadRequest().json(Error::new(err)));
+                break;
+            }
+        };
+        if file.size() + chunk.len() as u64 > upload.upload_length as u64 {
+            failed = Some(HttpResponse::BadRequest().json(Error::new("exceeds Upload-Length")));
+            break;
+        }
+        if let Err(err) = file.write(&chunk).await {
+            log::debug!("{:?}", err);
+            failed = Some(HttpResponse::InternalServerError().body(""));
+            break;
+        }
+    }
+    let size = file.size();
+    let partial = match file.finish().await {
+        Ok((partial, _)) => partial,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+    if let Err(err) = upload.update_offset(&mut conn, size).await {
+        log::debug!("{:?}", err);
+        return HttpResponse::InternalServerError().body("");
+    }
+    if let Some(response) = failed {
+        return response;
+    }
+
+    let mut response = HttpResponse::NoContent();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", upload.upload_offset.to_string()));
+    if !upload.is_complete() {
+        return response.finish();
+    }
+
+    let imported = Media::import_upload(
+        &state.pool,
+        &state.data_dir,
+        &upload_directory(&state.data_dir, &user.name),
+        &upload.file_name,
+        &partial,
+        None,
+        &state.option,
+    )
+    .await;
+    let media = match imported {
+        Ok((media, _)) => media,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            // 取り込めないファイルなので、やり直せないように削除する
+            let _ = upload.delete(&mut conn, &state.data_dir).await;
+            return HttpResponse::UnprocessableEntity().json(Error::new(err));
+        }
+    };
+    log::info!("uploaded {} by {}: {}", upload.file_name, user.name, *media.meta.media_id);
+
+    if let Err(err) = upload.update_media_id(&mut conn, &media.meta.media_id).await {
+        log::debug!("{:?}", err);
+        return HttpResponse::InternalServerError().body("");
+    }
+    response
+        .insert_header(("Media-Id", media.meta.media_id.as_str()))
+        .finish()
+}
+
+/// tus の termination でアップロードをやめる
+#[delete("/media/upload/resumable/{upload_id}")]
+pub async fn delete_resumable_upload(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let upload = match open_upload(&req, &path, &state).await {
+        Ok((_, upload)) => upload,
+        Err(response) => return response,
+    };
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+    match upload.delete(&mut conn, &state.data_dir).await {
+        Ok(_) => HttpResponse::NoContent()
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .finish(),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+/// 書き込み中のアップロードの印、終わったら外す
+struct Uploading {
+    uploading: Arc<Mutex<HashSet<String>>>,
+    upload_id: String,
+}
+
+impl Uploading {
+    /// 既に書き込み中であれば `None`
+    fn start(state: &AppState, upload_id: &str) -> Option<Self> {
+        if !state.uploading.lock().unwrap().insert(upload_id.to_owned()) {
+            return None;
+        }
+        Some(Uploading {
+            uploading: state.uploading.clone(),
+            upload_id: upload_id.to_owned(),
+        })
+    }
+}
+
+impl Drop for Uploading {
+    fn drop(&mut self) {
+        self.uploading.lock().unwrap().remove(&self.upload_id);
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|value| value.to_str().ok())
+}
+
+/// 認証して、`Tus-Resumable` が対応しているバージョンかを確かめる
+async fn authenticate_tus(req: &HttpRequest, state: &AppState) -> Result<User, HttpResponse> {
+    if let Some(version) = header(req, "Tus-Resumable") {
+        if version != TUS_VERSION {
+            return Err(HttpResponse::PreconditionFailed()
+                .insert_header(("Tus-Version", TUS_VERSION))
+                .finish());
+        }
+    }
+    match authenticate(req, &state.pool).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(unauthorized()),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            Err(HttpResponse::InternalServerError().body(""))
+        }
+    }
+}
+
+/// 認証したユーザのアップロードを取得する、他のユーザのものは見つからないものとする
+async fn open_upload(
+    req: &HttpRequest,
+    upload_id: &str,
+    state: &AppState,
+) -> Result<(User, Upload), HttpResponse> {
+    let user = authenticate_tus(req, state).await?;
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::debug!("{:?}", err);
+            return Err(HttpResponse::InternalServerError().body(""));
+        }
+    };
+    match Upload::get(&mut conn, upload_id).await {
+        Ok(Some(upload)) if upload.user_id == user.user_id => Ok((user, upload)),
+        Ok(_) => Err(HttpResponse::NotFound().body("")),
+        Err(err) => {
+            log::debug!("{:?}", err);
+            Err(HttpResponse::InternalServerError().body(""))
+        }
+    }
+}
+
+/// `Upload-Metadata` (`key base64,key base64`) から値を取り出す
+fn metadata_value(metadata: &str, keys: &[&str]) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut pair = pair.trim().splitn(2, ' ');
+        let key = pair.next()?;
+        if !keys.contains(&key) {
+            return None;
+        }
+        let value = base64::decode(pair.next()?.trim()).ok()?;
+        String::from_utf8(value).ok()
+    })
+}
@@ -1,42 +1,96 @@
+mod auth;
 mod handler;
 
-use std::path::{Path, PathBuf};
+use std::{collections::HashSet, net::SocketAddr, path::{Path, PathBuf}};
 use actix_web::{HttpServer, App, web};
 use anyhow::Result;
+use sqlx::SqlitePool;
 use handler::*;
+use crate::{media::MediaGenerateOption, watcher::{Watcher, WatcherStatus}};
+use std::sync::{Arc, Mutex};
 
-#[derive(Debug, Clone)]
 pub struct Server<'a> {
     pub data_dir: &'a Path,
-    pub port: u64,
+    pub address: SocketAddr,
+    /// `/` で返すフロントエンドのビルド結果
+    pub front_dir: &'a Path,
+    pub pool: SqlitePool,
+    /// アップロードされたファイルを取り込む設定
+    pub option: MediaGenerateOption,
+    /// サーバと一緒に動かす監視
+    pub watcher: Option<Watcher>,
 }
 
 #[derive(Clone, Debug)]
 pub struct AppState {
     pub data_dir: PathBuf,
+    pub pool: SqlitePool,
+    pub option: Arc<MediaGenerateOption>,
+    pub watcher: Option<Arc<Mutex<WatcherStatus>>>,
+    /// 書き込み中の再開できるアップロード、同じアップロードに同時に書き込まないようにする
+    pub uploading: Arc<Mutex<HashSet<String>>>,
 }
 
 impl <'a> Server<'a> {
-    pub async fn start(&self) -> Result<()> {
-        let bind_to = format!("0.0.0.0:{}", self.port);
+    pub async fn start(self) -> Result<()> {
+        let front_dir = self.front_dir.to_owned();
         let state = AppState {
             data_dir: self.data_dir.to_owned(),
+            pool: self.pool.clone(),
+            option: Arc::new(self.option),
+            watcher: self.watcher.as_ref().map(|watcher| watcher.status.clone()),
+            uploading: Arc::new(Mutex::new(HashSet::new())),
         };
+
+        // 監視はバックグラウンドで動かし、サーバが止まったら取り込み中のものを終えてから止める
+        let (shutdown, shutdown_rx) = tokio::sync::watch::channel(false);
+        let watcher = self.watcher.map(|watcher| {
+            tokio::spawn(async move {
+                if let Err(e) = watcher.run(shutdown_rx).await {
+                    log::error!("watcher stopped: {:?}", e);
+                }
+            })
+        });
+
         use actix_files::Files;
-        let _ = HttpServer::new(move || App::new()
+        HttpServer::new(move || App::new()
             .app_data(web::Data::new(state.clone()))
             .service(get_media_ids)
+            .service(search_media)
+            .service(get_media_timeline)
+            .service(get_media_meta_batch)
             .service(get_media_meta)
+            .service(update_media_meta)
             .service(get_media_origin)
             .service(get_media_thumb)
-            .service(Files::new("/", "./front/out")
+            .service(get_tags)
+            .service(add_media_tags)
+            .service(remove_media_tags)
+            .service(delete_media)
+            .service(restore_media)
+            .service(upload_media)
+            .service(resumable_upload_options)
+            .service(create_resumable_upload)
+            .service(get_resumable_upload)
+            .service(append_resumable_upload)
+            .service(delete_resumable_upload)
+            .service(get_watcher_status)
+            .service(get_jobs)
+            .service(web::resource("/dav").to(dav))
+            .service(web::resource("/dav/{path:.*}").to(dav))
+            .service(Files::new("/", &front_dir)
                 .prefer_utf8(true)
                 .index_file("index.html")
             )
         )
-            .bind(&bind_to)?
+            .bind(self.address)?
             .run()
             .await?;
+
+        let _ = shutdown.send(true);
+        if let Some(watcher) = watcher {
+            watcher.await?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,115 @@
+use anyhow::Result;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use sqlx::{prelude::*, query_as, SqliteConnection};
+
+/// FTP などでアップロードできるユーザ
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct User {
+    pub user_id: i64,
+    pub name: String,
+    /// argon2 でハッシュ化したパスワード
+    #[serde(skip)]
+    pub password_hash: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl User {
+    /// ユーザを追加する、既に同じ名前のユーザがいる場合はエラー
+    pub async fn create(conn: &mut SqliteConnection, name: &str, password: &str) -> Result<Self> {
+        ensure!(is_valid_name(name), "invalid user name: {}", name);
+        ensure!(
+            User::get_by_name(&mut *conn, name).await?.is_none(),
+            "user already exists: {}",
+            name
+        );
+
+        // `returning` で受け取ると、すぐに終了する場合にコミットされないことがある
+        let password_hash = hash_password(password.to_owned()).await?;
+        let _ = sqlx::query("insert into users (name, password_hash, created_at) values (?, ?, ?)")
+            .bind(name)
+            .bind(password_hash)
+            .bind(Utc::now().naive_utc())
+            .execute(&mut *conn)
+            .await?;
+        User::get_by_name(conn, name)
+            .await?
+            .ok_or_else(|| anyhow!("failed to add user: {}", name))
+    }
+
+    pub async fn get_by_name(conn: &mut SqliteConnection, name: &str) -> Result<Option<Self>> {
+        let user = query_as("select * from users where name = ?")
+            .bind(name)
+            .fetch_optional(conn)
+            .await?;
+        Ok(user)
+    }
+
+    pub async fn list(conn: &mut SqliteConnection) -> Result<Vec<Self>> {
+        let users = query_as("select * from users order by name")
+            .fetch_all(conn)
+            .await?;
+        Ok(users)
+    }
+
+    /// ユーザを削除する、削除できたかどうかを返す
+    pub async fn delete(conn: &mut SqliteConnection, name: &str) -> Result<bool> {
+        let result = sqlx::query("delete from users where name = ?")
+            .bind(name)
+            .execute(conn)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// 名前とパスワードが一致するユーザを返す
+    pub async fn authenticate(
+        conn: &mut SqliteConnection,
+        name: &str,
+        password: &str,
+    ) -> Result<Option<Self>> {
+        let user = match User::get_by_name(conn, name).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        // ハッシュの計算は重いのでブロッキングするスレッドで行う
+        let password_hash = user.password_hash.clone();
+        let password = password.to_owned();
+        let verified = tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&password_hash)
+                .map(|hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok()
+                })
+                .unwrap_or(false)
+        })
+        .await?;
+
+        Ok(if verified { Some(user) } else { None })
+    }
+}
+
+/// ユーザ名はそのままアップロード先のディレクトリ名にするので、英数字と `-` `_` `.` のみ
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+async fn hash_password(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow!("failed to hash password: {}", e))
+    })
+    .await?
+}
@@ -0,0 +1,320 @@
+use crate::media::{common::*, JobWorkers, MediaGenerateOption, MediaMeta, ScanOption};
+use anyhow::Result;
+use notify::DebouncedEvent;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{mpsc, watch};
+
+// イベントをまとめる時間
+const DEBOUNCE_DURATION: Duration = Duration::from_secs(5);
+
+// 書き込みが終わったかを確かめる間隔
+const STABILITY_INTERVAL: Duration = Duration::from_secs(1);
+
+// サイズと更新日時がこの回数続けて変わらなければ書き込みが終わったとみなす
+const STABLE_POLLS: u32 = 3;
+
+/// 書き込みが終わるのを待っているファイル
+#[derive(Debug, Clone)]
+struct Pending {
+    size: u64,
+    modified: SystemTime,
+    /// サイズと更新日時が変わらなかった回数
+    stable: u32,
+}
+
+/// 取り込みの状況
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct WatcherStatus {
+    /// 監視するディレクトリ
+    pub origin: String,
+    /// 書き込みが終わるのを待っているファイル
+    /// 書き込みが終わったものは jobs に追加する
+    pub pending: Vec<String>,
+}
+
+/// ディレクトリを監視してメディアを生成する
+pub struct Watcher {
+    pub pool: SqlitePool,
+    /// 監視するディレクトリ
+    pub origin: PathBuf,
+    pub jobs: JobWorkers,
+    /// 取り込むワーカーの数
+    pub workers: usize,
+    /// 取り込むファイルを探す際のルール、監視中のイベントにも使う
+    pub scan: ScanOption,
+    pub status: Arc<Mutex<WatcherStatus>>,
+}
+
+impl Watcher {
+    pub fn new(
+        pool: SqlitePool,
+        origin: &Path,
+        data_dir: &Path,
+        option: MediaGenerateOption,
+        workers: usize,
+    ) -> Result<Self> {
+        // イベントのパスと metas の origin を比べるので、フルパスにしておく
+        let origin = origin.canonicalize()?;
+        let status = WatcherStatus {
+            origin: origin.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        Ok(Watcher {
+            scan: option.scan.clone(),
+            jobs: JobWorkers::new(pool.clone(), data_dir, option),
+            pool,
+            origin,
+            workers,
+            status: Arc::new(Mutex::new(status)),
+        })
+    }
+
+    /// 停止していた間の変更を取り込んでから監視を始める
+    /// `shutdown` が `true` になったら、取り込み中のものを終えてから止まる
+    pub async fn run(&self, shutdown: watch::Receiver<bool>) -> Result<()> {
+        let entries = self.catch_up().await?;
+        let (watched, worked) = tokio::join!(
+            self.watch(entries, shutdown.clone()),
+            self.jobs.run(self.workers, shutdown)
+        );
+        watched.and(worked)
+    }
+
+    /// ディレクトリと metas を突き合わせる
+    /// 見つからなくなったものを記録し、まだ取り込んでいないファイルを返す
+    pub async fn catch_up(&self) -> Result<Vec<PathBuf>> {
+        let mut conn = self.pool.acquire().await?;
+        let metas = MediaMeta::list_by_origin_dir(&mut conn, &self.origin).await?;
+
+        let mut known = HashSet::with_capacity(metas.len());
+        for mut meta in metas {
+            let exists = Path::new(&meta.origin).is_file();
+            match (exists, meta.missing_at.is_some()) {
+                (true, true) => {
+                    let origin = meta.origin.clone();
+                    meta.update_origin(&mut conn, &origin).await?;
+                }
+                (false, false) => {
+                    log::info!("missing origin: {}", meta.origin);
+                    meta.mark_missing(&mut conn).await?;
+                }
+                _ => {}
+            }
+            if exists {
+                known.insert(PathBuf::from(meta.origin));
+            }
+        }
+
+        // origin は実体のパスで保存しているので、比べる時だけ実体のパスにする
+        let mut entries = vec![];
+        for entry in get_image_filenames(&self.origin, &self.scan) {
+            let real = match entry.canonicalize() {
+                Ok(real) => real,
+                Err(_) => continue,
+            };
+            if known.contains(&real) {
+                continue;
+            }
+            // リンクを辿った先のファイルは `origin` の外にあるので個別に探す
+            if !real.starts_with(&self.origin)
+                && !MediaMeta::list_by_origin_path(&mut conn, &real)
+                    .await?
+                    .is_empty()
+            {
+                continue;
+            }
+            entries.push(entry);
+        }
+        drop(conn);
+        log::info!("catch up {} files in {:#?}", entries.len(), self.origin);
+
+        Ok(entries)
+    }
+
+    /// notify のイベントを tokio のチャネルに流して処理する
+    /// 追加されたファイルは書き込みが終わるのを待ってから jobs に追加する
+    async fn watch(
+        &self,
+        entries: Vec<PathBuf>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        use notify::{watcher, RecursiveMode, Watcher};
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = watcher(tx, DEBOUNCE_DURATION)?;
+        watcher.watch(&self.origin, RecursiveMode::Recursive)?;
+
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            while let Ok(event) = rx.recv() {
+                if event_tx.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut pending = HashMap::new();
+        self.wait_for(&mut pending, entries);
+        self.update_pending(&pending);
+
+        let mut interval = tokio::time::interval(STABILITY_INTERVAL);
+        while !*shutdown.borrow() {
+            tokio::select! {
+                event = event_rx.recv() => match event {
+                    Some(event) => match self.handle(event).await {
+                        Ok(entries) => self.wait_for(&mut pending, entries),
+                        Err(e) => log::warn!("watch error: {:?}", e),
+                    },
+                    None => break,
+                },
+                _ = interval.tick() => {
+                    let entries = poll(&mut pending);
+                    self.update_pending(&pending);
+                    if let Err(e) = self.enqueue(entries).await {
+                        log::warn!("enqueue error: {:?}", e);
+                    }
+                }
+                _ = shutdown.changed() => {}
+            }
+            self.update_pending(&pending);
+        }
+
+        // 書き込み中のものは次に起動した時に取り込む
+        log::info!("stop watching, {} files left pending", pending.len());
+        Ok(())
+    }
+
+    /// 書き込みが終わるのを待つファイルに加える
+    /// 既に待っている場合は、次に確かめる時に変更を検知するのでそのままにする
+    fn wait_for(&self, pending: &mut HashMap<PathBuf, Pending>, entries: Vec<PathBuf>) {
+        for entry in entries {
+            if !self.scan.matches(&self.origin, &entry) || pending.contains_key(&entry) {
+                continue;
+            }
+            if let Some((size, modified)) = stat(&entry) {
+                let _ = pending.insert(
+                    entry,
+                    Pending {
+                        size,
+                        modified,
+                        stable: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    fn update_pending(&self, pending: &HashMap<PathBuf, Pending>) {
+        let mut status = self.status.lock().unwrap();
+        status.pending = pending
+            .keys()
+            .map(|entry| entry.to_string_lossy().to_string())
+            .collect();
+        status.pending.sort();
+    }
+
+    /// イベントを処理して、取り込むファイルを返す
+    async fn handle(&self, event: DebouncedEvent) -> Result<Vec<PathBuf>> {
+        let entries = match event {
+            DebouncedEvent::Create(origin) | DebouncedEvent::Write(origin) => vec![origin],
+            DebouncedEvent::Rename(from, to) => self.rename(&from, &to).await?,
+            DebouncedEvent::Remove(origin) => {
+                self.remove(&origin).await?;
+                vec![]
+            }
+            // イベントを取りこぼした可能性があるので突き合わせ直す
+            DebouncedEvent::Rescan => self.catch_up().await?,
+            DebouncedEvent::Error(e, path) => {
+                log::debug!("watch error: {:?} {:?}", e, path);
+                vec![]
+            }
+            _ => vec![],
+        };
+        Ok(entries)
+    }
+
+    /// 書き込みが終わったファイルを jobs に追加する
+    async fn enqueue(&self, entries: Vec<PathBuf>) -> Result<()> {
+        let entries = entries
+            .into_iter()
+            .filter_map(|entry| entry.canonicalize().ok())
+            .collect::<Vec<_>>();
+        self.jobs.enqueue(&entries).await
+    }
+
+    /// ファイルかディレクトリが移動されたので、以下にあるもののパスを付け替える
+    /// 取り込んでいないファイルが移動してきた場合はそのファイルを返す
+    async fn rename(&self, from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+        let mut conn = self.pool.acquire().await?;
+        let metas = MediaMeta::list_by_origin_path(&mut conn, from).await?;
+        if metas.is_empty() {
+            if to.is_dir() {
+                return Ok(self.scan.scan_dir(&self.origin, to));
+            }
+            return Ok(vec![to.to_owned()]);
+        }
+
+        for mut meta in metas {
+            let origin = match Path::new(&meta.origin).strip_prefix(from) {
+                Ok(rest) if rest.as_os_str().is_empty() => to.to_owned(),
+                Ok(rest) => to.join(rest),
+                Err(_) => continue,
+            };
+            log::info!("rename origin: {} -> {:#?}", meta.origin, origin);
+            meta.update_origin(&mut conn, &origin.to_string_lossy())
+                .await?;
+        }
+        Ok(vec![])
+    }
+
+    /// ファイルかディレクトリが削除されたので、以下にあるものを見つからないものとして記録する
+    async fn remove(&self, origin: &Path) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        for mut meta in MediaMeta::list_by_origin_path(&mut conn, origin).await? {
+            if meta.missing_at.is_none() {
+                log::info!("missing origin: {}", meta.origin);
+                meta.mark_missing(&mut conn).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// サイズと更新日時を確かめて、書き込みが終わったファイルを返す
+/// 見つからなくなったファイルは待つのをやめる
+fn poll(pending: &mut HashMap<PathBuf, Pending>) -> Vec<PathBuf> {
+    let mut ready = vec![];
+    pending.retain(|entry, pending| {
+        let (size, modified) = match stat(entry) {
+            Some(stat) => stat,
+            None => return false,
+        };
+        if size == pending.size && modified == pending.modified {
+            pending.stable += 1;
+        } else {
+            *pending = Pending {
+                size,
+                modified,
+                stable: 0,
+            };
+        }
+        if pending.stable < STABLE_POLLS {
+            return true;
+        }
+        ready.push(entry.clone());
+        false
+    });
+    ready
+}
+
+fn stat(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}